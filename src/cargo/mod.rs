@@ -0,0 +1,739 @@
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Error;
+use cargo_metadata::camino::Utf8PathBuf;
+use cargo_metadata::ArtifactDebuginfo;
+use cargo_metadata::ArtifactProfile;
+use cargo_metadata::Message;
+use is_executable::IsExecutable;
+use std::env;
+use std::fs;
+use std::io::BufReader;
+use std::iter;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use structopt::StructOpt;
+
+mod runner;
+
+/// Name of the profile used by `--profiling`. Artifacts of a profile are
+/// stored in its own directory, so other builds are not invalidated.
+const PROFILING_PROFILE: &str = "profiling";
+
+/// Built bin file.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BinFile {
+    pub path: PathBuf,
+    /// Name of the cargo target.
+    pub name: String,
+    pub is_bench: bool,
+    /// `.dSYM`,
+    pub extra_files: Vec<PathBuf>,
+    pub profile: ArtifactProfile,
+    /// Target triple passed by `--target`.
+    pub target: Option<String>,
+    /// `target.<triple>.runner` of cargo config, which runs the binary.
+    pub runner: Vec<String>,
+}
+
+impl BinFile {
+    /// Settings of the profile used to build the binary, like
+    /// `opt-level=3, debuginfo=2`.
+    pub fn profile_summary(&self) -> String {
+        let mut summary = format!(
+            "opt-level={}, debuginfo={}",
+            self.profile.opt_level, self.profile.debuginfo
+        );
+        if self.profile.debug_assertions {
+            summary.push_str(", debug-assertions");
+        }
+        summary
+    }
+
+    /// Fails if the binary cannot run on this machine.
+    ///
+    /// Binaries of other architectures need emulators like qemu-user, and
+    /// profilers would only see the emulator.
+    pub fn check_runnable(&self) -> Result<(), Error> {
+        let triple = match &self.target {
+            Some(triple) => triple,
+            None => return Ok(()),
+        };
+        let arch = triple.split('-').next().unwrap_or_default();
+        if is_native_arch(arch) {
+            return Ok(());
+        }
+
+        let runner = if self.runner.is_empty() {
+            String::new()
+        } else {
+            format!(" with the runner `{}`", self.runner.join(" "))
+        };
+        bail!(
+            "{} is built for `{}`, which cannot run natively on {}. Profiling it{} would only \
+             profile the emulator, so profile it on a {} machine instead",
+            self.name,
+            triple,
+            env::consts::ARCH,
+            runner,
+            arch
+        )
+    }
+}
+
+/// Returns true if binaries for `arch` of a target triple run on this machine
+/// without emulators.
+fn is_native_arch(arch: &str) -> bool {
+    let x86 = |arch: &str| matches!(arch, "i386" | "i586" | "i686");
+
+    match env::consts::ARCH {
+        "x86_64" => arch == "x86_64" || x86(arch),
+        "x86" => x86(arch),
+        "arm" => arch.starts_with("arm") && !arch.starts_with("arm64") || arch.starts_with("thumb"),
+        "riscv64" => arch.starts_with("riscv64"),
+        host => arch == host,
+    }
+}
+
+#[derive(Debug, Clone, StructOpt)]
+#[structopt(setting = structopt::clap::AppSettings::TrailingVarArg)]
+pub struct CargoTarget {
+    /// Package to build. Can be used multiple times.
+    #[structopt(short = "p", long, value_name = "SPEC", number_of_values = 1)]
+    package: Vec<String>,
+
+    /// Build all packages of the workspace.
+    #[structopt(long)]
+    workspace: bool,
+
+    #[structopt(long)]
+    lib: bool,
+
+    #[structopt(long)]
+    release: bool,
+
+    /// Build with a profile defined in `Cargo.toml`, like `[profile.profiling]`.
+    #[structopt(long, value_name = "NAME", conflicts_with = "release")]
+    profile: Option<String>,
+
+    /// Build optimized binaries with debuginfo, using a profile named
+    /// `profiling`. Unless `[profile.profiling]` is defined in `Cargo.toml`, it
+    /// inherits `release` with `debug = true`. Artifacts are stored in
+    /// `target/profiling`, so other builds are not invalidated.
+    #[structopt(long, conflicts_with_all = &["release", "profile"])]
+    profiling: bool,
+
+    /// Build with `-C force-frame-pointers=yes`, so `--call-graph fp` works.
+    #[structopt(long, requires = "profiling")]
+    frame_pointers: bool,
+
+    /// Binary to build. Can be used multiple times.
+    #[structopt(long, value_name = "NAME", number_of_values = 1)]
+    bin: Vec<String>,
+
+    /// Build all binaries.
+    #[structopt(long)]
+    bins: bool,
+
+    #[structopt(long)]
+    bench: Option<String>,
+
+    #[structopt(long)]
+    benches: bool,
+
+    #[structopt(long)]
+    test: Option<String>,
+
+    #[structopt(long)]
+    tests: bool,
+
+    #[structopt(long)]
+    example: Option<String>,
+
+    #[structopt(long)]
+    examples: bool,
+
+    #[structopt(long)]
+    features: Option<Vec<String>>,
+
+    #[structopt(long)]
+    all_features: bool,
+
+    #[structopt(long)]
+    no_default_features: bool,
+
+    /// Build for the target triple, like `x86_64-unknown-linux-musl`.
+    #[structopt(long, value_name = "TRIPLE")]
+    target: Option<String>,
+
+    /// Path to `Cargo.toml`.
+    #[structopt(long, value_name = "PATH")]
+    manifest_path: Option<PathBuf>,
+
+    /// Directory for all generated artifacts.
+    #[structopt(long, value_name = "DIRECTORY")]
+    target_dir: Option<PathBuf>,
+
+    /// Require `Cargo.lock` to be up to date.
+    #[structopt(long)]
+    locked: bool,
+
+    /// Run without accessing the network.
+    #[structopt(long)]
+    offline: bool,
+
+    /// Require `Cargo.lock` and cache to be up to date.
+    #[structopt(long)]
+    frozen: bool,
+
+    /// Unstable flags of cargo. Can be used multiple times.
+    #[structopt(short = "Z", long, value_name = "FLAG", number_of_values = 1)]
+    unstable_flags: Vec<String>,
+
+    /// Arguments passed to the target binary.
+    ///
+    /// To pass flags, precede child args with `--`,
+    /// e.g. `cargo profile subcommand -- -t test1.txt --slow-mode`.
+    #[structopt(value_name = "ARGS")]
+    target_args: Vec<String>,
+}
+
+impl CargoTarget {
+    pub fn args(&self) -> &[String] {
+        &self.target_args
+    }
+
+    /// Returns the cargo command building the target, and true if it builds
+    /// benchmarks.
+    ///
+    /// `define_profiling` is false if the user defined `[profile.profiling]`.
+    fn command(&self, define_profiling: bool) -> (Command, bool) {
+        let release = self.release;
+        let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+
+        let mut is_bench = false;
+        let mut cmd = Command::new(&cargo);
+
+        if self.benches || self.bench.is_some() {
+            is_bench = true;
+
+            cmd.arg("bench").arg("--no-run");
+
+            if self.benches {
+                cmd.arg("--benches");
+            }
+
+            if let Some(target) = &self.bench {
+                cmd.arg("--bench").arg(target);
+            }
+        } else if self.tests || self.test.is_some() {
+            cmd.arg("test").arg("--no-run");
+
+            if release {
+                cmd.arg("--release");
+            }
+
+            if self.tests {
+                cmd.arg("--tests");
+            }
+
+            if let Some(target) = &self.test {
+                cmd.arg("--test").arg(target);
+            }
+        } else {
+            cmd.arg("build");
+
+            if release {
+                cmd.arg("--release");
+            }
+
+            if self.examples {
+                cmd.arg("--examples");
+            }
+
+            if let Some(target) = &self.example {
+                cmd.arg("--example").arg(target);
+            }
+        }
+
+        if let Some(profile) = &self.profile {
+            cmd.arg("--profile").arg(profile);
+        }
+
+        if self.profiling {
+            cmd.arg("--profile").arg(PROFILING_PROFILE);
+            if define_profiling {
+                for config in &["inherits = \"release\"", "debug = true"] {
+                    cmd.arg("--config")
+                        .arg(format!("profile.{}.{}", PROFILING_PROFILE, config));
+                }
+            }
+        }
+
+        if self.frame_pointers {
+            add_rustflags(&mut cmd, &["-C", "force-frame-pointers=yes"]);
+        }
+
+        if self.lib {
+            cmd.arg("--lib");
+        }
+
+        for bin in &self.bin {
+            cmd.arg("--bin").arg(bin);
+        }
+
+        if self.bins {
+            cmd.arg("--bins");
+        }
+
+        for package in &self.package {
+            cmd.arg("--package").arg(package);
+        }
+
+        if self.workspace {
+            cmd.arg("--workspace");
+        }
+
+        if let Some(features) = &self.features {
+            cmd.arg("--features").arg(features.join(","));
+        }
+
+        if self.all_features {
+            cmd.arg("--all-features");
+        }
+
+        if self.no_default_features {
+            cmd.arg("--no-default-features");
+        }
+
+        if let Some(triple) = &self.target {
+            cmd.arg("--target").arg(triple);
+        }
+
+        if let Some(path) = &self.manifest_path {
+            cmd.arg("--manifest-path").arg(path);
+        }
+
+        if let Some(dir) = &self.target_dir {
+            cmd.arg("--target-dir").arg(dir);
+        }
+
+        if self.locked {
+            cmd.arg("--locked");
+        }
+
+        if self.offline {
+            cmd.arg("--offline");
+        }
+
+        if self.frozen {
+            cmd.arg("--frozen");
+        }
+
+        for flag in &self.unstable_flags {
+            cmd.arg("-Z").arg(flag);
+        }
+
+        cmd.arg("--message-format=json");
+
+        (cmd, is_bench)
+    }
+
+    /// Returns the program and arguments of `target.<triple>.runner`, which
+    /// cargo would use to run binaries.
+    fn runner(&self) -> Result<Vec<String>, Error> {
+        let triple = match &self.target {
+            Some(triple) => triple.clone(),
+            // Runners of the host are used without `--target`, too.
+            None => match runner::host_triple() {
+                Ok(triple) => triple,
+                Err(_) => return Ok(vec![]),
+            },
+        };
+
+        // Like cargo, config files are searched from the current directory,
+        // even if `--manifest-path` is given.
+        let dir = env::current_dir().context("failed to get the current directory")?;
+
+        runner::runner(&triple, &dir)
+    }
+
+    /// Returns true if `[profile.profiling]` is defined in the manifest of the
+    /// workspace or in config files of cargo.
+    fn has_profiling_profile(&self) -> Result<bool, Error> {
+        let manifest = cargo_workspace(self)?.join("Cargo.toml");
+        let dir = env::current_dir().context("failed to get the current directory")?;
+
+        for file in iter::once(manifest).chain(runner::config_files(&dir)) {
+            let config = fs::read_to_string(&file)
+                .with_context(|| format!("failed to read {}", file.display()))?
+                .parse::<toml::Table>()
+                .with_context(|| format!("failed to parse {}", file.display()))?;
+            if config
+                .get("profile")
+                .and_then(|profiles| profiles.get(PROFILING_PROFILE))
+                .is_some()
+            {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+/// Adds `flags` to flags of rustc.
+///
+/// Flags set by the environment override `build.rustflags` of config files, so
+/// `flags` are appended to them only if the user set them. Otherwise they are
+/// passed as `build.rustflags` using `--config`, which cargo merges with
+/// config files.
+fn add_rustflags(cmd: &mut Command, flags: &[&str]) {
+    if let Ok(encoded) = env::var("CARGO_ENCODED_RUSTFLAGS") {
+        let mut encoded = encoded;
+        for flag in flags {
+            if !encoded.is_empty() {
+                encoded.push('\x1f');
+            }
+            encoded.push_str(flag);
+        }
+        cmd.env("CARGO_ENCODED_RUSTFLAGS", encoded);
+        return;
+    }
+
+    if let Ok(mut rustflags) = env::var("RUSTFLAGS") {
+        for flag in flags {
+            if !rustflags.is_empty() {
+                rustflags.push(' ');
+            }
+            rustflags.push_str(flag);
+        }
+        cmd.env("RUSTFLAGS", rustflags);
+        return;
+    }
+
+    let flags = flags
+        .iter()
+        .map(|flag| format!("{:?}", flag))
+        .collect::<Vec<_>>();
+    cmd.arg("--config")
+        .arg(format!("build.rustflags=[{}]", flags.join(", ")));
+}
+
+/// Warns if stacks of `binary` would be misleading or hard to read.
+fn check_profile(binary: &BinFile) {
+    if binary.profile.opt_level == "0" {
+        eprintln!(
+            "warning: {} is not optimized, so the profile may differ from optimized builds. Use \
+             `--profiling` to build optimized binaries with debuginfo",
+            binary.name
+        );
+    }
+
+    if binary.profile.debuginfo == ArtifactDebuginfo::None {
+        eprintln!(
+            "warning: {} has no debuginfo, so inlined functions and source lines are not \
+             shown. Use `--profiling` to build optimized binaries with debuginfo",
+            binary.name
+        );
+    }
+}
+
+/// Compile one or more targets.
+pub fn compile(target: &CargoTarget) -> Result<Vec<BinFile>, Error> {
+    let define_profiling = target.profiling && !target.has_profiling_profile()?;
+    let (mut cmd, is_bench) = target.command(define_profiling);
+    let runner = target.runner()?;
+
+    let cmd_str = format!("{:?}", cmd);
+
+    let mut child = cmd
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .with_context(|| format!("failed to spawn cargo\n{}", cmd_str))?;
+
+    let mut binaries = vec![];
+    let reader = BufReader::new(child.stdout.take().unwrap());
+    for message in Message::parse_stream(reader) {
+        let message =
+            message.with_context(|| format!("failed to read output of cargo\n{}", cmd_str))?;
+        match message {
+            Message::CompilerMessage(..) => {}
+            Message::CompilerArtifact(mut artifact) => {
+                // Unit tests of libraries, built by `--lib` of benches and
+                // tests, have the kind of the library.
+                if artifact.profile.test
+                    || artifact.target.kind.contains(&"bin".to_string())
+                    || artifact.target.kind.contains(&"test".to_string())
+                    || artifact.target.kind.contains(&"bench".to_string())
+                    || artifact.target.kind.contains(&"example".to_string())
+                {
+                    let mut executable = None;
+
+                    artifact.filenames.retain(|path| {
+                        if executable.is_none() && path.as_std_path().is_executable() {
+                            executable = Some(path.clone().into_std_path_buf());
+                            return false;
+                        }
+
+                        true
+                    });
+
+                    binaries.push(BinFile {
+                        path: match executable {
+                            Some(v) => v,
+                            None => continue,
+                        },
+                        name: artifact.target.name,
+                        is_bench,
+                        extra_files: artifact
+                            .filenames
+                            .into_iter()
+                            .map(Utf8PathBuf::into_std_path_buf)
+                            .collect(),
+                        profile: artifact.profile,
+                        target: target.target.clone(),
+                        runner: runner.clone(),
+                    });
+                    continue;
+                }
+
+                if artifact.target.kind == vec!["lib".to_string()] {
+                    continue;
+                }
+            }
+            Message::BuildFinished(finished) if !finished.success => {
+                bail!("Failed to compile binary using cargo\n{}", cmd_str)
+            }
+            _ => (),
+        }
+    }
+
+    let _output = child
+        .wait()
+        .with_context(|| format!("Couldn't get cargo's exit status\n{}", cmd_str))?;
+
+    if binaries.is_empty() {
+        bail!("cargo did not produce any useful binary\n{}", cmd_str)
+    }
+
+    binaries.sort_by_key(|b| b.path.clone());
+    for binary in &binaries {
+        check_profile(binary);
+    }
+
+    Ok(binaries)
+}
+
+/// Returns the root of the workspace containing the target.
+pub fn cargo_workspace(target: &CargoTarget) -> Result<PathBuf, Error> {
+    let mut cmd = cargo_metadata::MetadataCommand::new();
+    if let Some(path) = &target.manifest_path {
+        cmd.manifest_path(path);
+    }
+
+    let md = cmd.no_deps().exec().context("cargo metadata failed")?;
+
+    Ok(md.workspace_root.into_std_path_buf())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn cargo_args(args: &[&str]) -> Vec<String> {
+        let target = CargoTarget::from_iter(std::iter::once("target").chain(args.iter().copied()));
+        let (cmd, _) = target.command(true);
+
+        cmd.get_args()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn selection_is_forwarded() {
+        assert_eq!(
+            cargo_args(&[
+                "-p",
+                "parser",
+                "--bin",
+                "a",
+                "--bin",
+                "b",
+                "--manifest-path",
+                "crates/Cargo.toml",
+                "--target-dir",
+                "/tmp/target",
+                "--locked",
+                "-Z",
+                "build-std",
+                "--no-default-features",
+                "--",
+                "arg",
+            ]),
+            vec![
+                "build",
+                "--bin",
+                "a",
+                "--bin",
+                "b",
+                "--package",
+                "parser",
+                "--no-default-features",
+                "--manifest-path",
+                "crates/Cargo.toml",
+                "--target-dir",
+                "/tmp/target",
+                "--locked",
+                "-Z",
+                "build-std",
+                "--message-format=json",
+            ]
+        );
+    }
+
+    #[test]
+    fn profiling_profile_is_used() {
+        assert_eq!(
+            cargo_args(&["--profiling", "--bench", "parser"]),
+            vec![
+                "bench",
+                "--no-run",
+                "--bench",
+                "parser",
+                "--profile",
+                "profiling",
+                "--config",
+                "profile.profiling.inherits = \"release\"",
+                "--config",
+                "profile.profiling.debug = true",
+                "--message-format=json",
+            ]
+        );
+    }
+
+    #[test]
+    fn profiling_profile_of_workspace_is_not_overridden() {
+        let dir = TempDir::new("cargo-profile").unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        let manifest = dir.path().join("Cargo.toml");
+        let package = "[package]\nname = \"a\"\nversion = \"0.1.0\"\n";
+        let profile = "[profile.profiling]\ninherits = \"release\"\ndebug = \"line-tables-only\"\n";
+
+        let target = CargoTarget::from_iter(&[
+            "target",
+            "--profiling",
+            "--manifest-path",
+            manifest.to_str().unwrap(),
+        ]);
+        fs::write(&manifest, package).unwrap();
+        assert!(!target.has_profiling_profile().unwrap());
+        fs::write(&manifest, format!("{}{}", package, profile)).unwrap();
+        assert!(target.has_profiling_profile().unwrap());
+
+        let (cmd, _) = target.command(false);
+        assert!(!cmd.get_args().any(|arg| arg == "--config"));
+    }
+
+    #[test]
+    fn profiles_with_debuginfo_levels_are_reported() {
+        let dir = TempDir::new("cargo-profile").unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("src/main.rs"), "fn main() {}").unwrap();
+        let manifest = dir.path().join("Cargo.toml");
+        fs::write(
+            &manifest,
+            "[package]\nname = \"a\"\nversion = \"0.1.0\"\nedition = \"2018\"\n\n[profile.profiling]\ninherits = \
+             \"release\"\ndebug = \"line-tables-only\"\n",
+        )
+        .unwrap();
+
+        let target = CargoTarget::from_iter(&[
+            "target",
+            "--profile",
+            "profiling",
+            "--manifest-path",
+            manifest.to_str().unwrap(),
+            "--target-dir",
+            dir.path().join("target").to_str().unwrap(),
+        ]);
+        let binaries = compile(&target).unwrap();
+        assert_eq!(binaries.len(), 1);
+        assert_eq!(
+            binaries[0].profile_summary(),
+            "opt-level=3, debuginfo=line-tables-only"
+        );
+    }
+
+    #[test]
+    fn custom_profile_is_used() {
+        for (mode, target) in &[("bench", "--bench"), ("test", "--test")] {
+            let args = cargo_args(&["--profile", "profiling", target, "parser"]);
+            assert_eq!(args[0], *mode);
+            assert!(!args.contains(&"--debug".to_string()), "{:?}", args);
+            assert!(
+                args.windows(2).any(|w| w == ["--profile", "profiling"]),
+                "{:?}",
+                args
+            );
+        }
+
+        assert_eq!(
+            cargo_args(&["--profile", "profiling", "--bin", "a"]),
+            vec![
+                "build",
+                "--profile",
+                "profiling",
+                "--bin",
+                "a",
+                "--message-format=json",
+            ]
+        );
+    }
+
+    #[test]
+    fn target_is_forwarded() {
+        assert_eq!(
+            cargo_args(&["--target", "x86_64-unknown-linux-musl", "--release"]),
+            vec![
+                "build",
+                "--release",
+                "--target",
+                "x86_64-unknown-linux-musl",
+                "--message-format=json",
+            ]
+        );
+    }
+
+    #[test]
+    fn native_archs_are_detected() {
+        assert!(is_native_arch(env::consts::ARCH));
+        assert!(!is_native_arch("wasm32"));
+        if env::consts::ARCH == "x86_64" {
+            assert!(is_native_arch("i686"));
+            assert!(!is_native_arch("aarch64"));
+        }
+    }
+
+    #[test]
+    fn lib_is_forwarded_to_benches() {
+        let args = cargo_args(&["--lib", "--bench", "parser", "--workspace"]);
+        assert!(!args.contains(&"--debug".to_string()), "{:?}", args);
+        assert_eq!(
+            args,
+            vec![
+                "bench",
+                "--no-run",
+                "--bench",
+                "parser",
+                "--lib",
+                "--workspace",
+                "--message-format=json",
+            ]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+//! Runners configured for cargo, like `target.<triple>.runner`.
+
+use anyhow::Context;
+use anyhow::Error;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use toml::Value;
+
+/// Returns the triple of the host, like `x86_64-unknown-linux-gnu`.
+pub(super) fn host_triple() -> Result<String, Error> {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".into());
+    let output = Command::new(&rustc)
+        .arg("-vV")
+        .output()
+        .with_context(|| format!("failed to run `{} -vV`", rustc))?;
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .map(|host| host.trim().to_string())
+        .with_context(|| format!("`{} -vV` did not print the host", rustc))
+}
+
+/// Returns the program and arguments used to run binaries of `triple`.
+///
+/// Like cargo, `CARGO_TARGET_<TRIPLE>_RUNNER` is used first, and then config
+/// files in `.cargo` of `dir` and its ancestors, and `$CARGO_HOME`. Runners of
+/// `cfg(..)` are not supported.
+///
+/// The environment variable and runners given as strings are split on
+/// whitespace, like cargo does, so programs with spaces in their paths can be
+/// configured only as arrays.
+pub(super) fn runner(triple: &str, dir: &Path) -> Result<Vec<String>, Error> {
+    let var = format!(
+        "CARGO_TARGET_{}_RUNNER",
+        triple.to_uppercase().replace(['-', '.'], "_")
+    );
+    if let Ok(runner) = env::var(&var) {
+        return Ok(runner.split_whitespace().map(String::from).collect());
+    }
+
+    // Printed only if no runner of the triple is found.
+    let mut ignored = vec![];
+
+    for file in config_files(dir) {
+        let config = fs::read_to_string(&file)
+            .with_context(|| format!("failed to read {}", file.display()))?
+            .parse::<toml::Table>()
+            .with_context(|| format!("failed to parse {}", file.display()))?;
+        let targets = match config.get("target").and_then(Value::as_table) {
+            Some(targets) => targets,
+            None => continue,
+        };
+
+        for (key, target) in targets {
+            if key.starts_with("cfg(") && target.get("runner").is_some() {
+                ignored.push(format!("`target.'{}'` in {}", key, file.display()));
+            }
+        }
+
+        let runner = match targets.get(triple).and_then(|target| target.get("runner")) {
+            Some(runner) => runner,
+            None => continue,
+        };
+        let mut runner = match runner {
+            Value::String(runner) => runner.split_whitespace().map(String::from).collect(),
+            Value::Array(args) => args
+                .iter()
+                .filter_map(|arg| arg.as_str().map(String::from))
+                .collect::<Vec<_>>(),
+            _ => continue,
+        };
+
+        // Like cargo, relative paths are relative to the parent of `.cargo`.
+        if let (Some(program), Some(root)) = (runner.first_mut(), file.ancestors().nth(2)) {
+            if program.contains('/') && Path::new(program).is_relative() {
+                *program = root.join(&*program).to_string_lossy().into_owned();
+            }
+        }
+
+        return Ok(runner);
+    }
+
+    for target in ignored {
+        eprintln!(
+            "warning: runner of {} is ignored. Configure `target.{}.runner` instead",
+            target, triple
+        );
+    }
+
+    Ok(vec![])
+}
+
+/// Config files of cargo, in order of precedence.
+pub(super) fn config_files(dir: &Path) -> Vec<PathBuf> {
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo")));
+
+    dir.ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .chain(cargo_home)
+        .filter_map(|dir| {
+            // Cargo uses the file without the extension if both exist.
+            let legacy = dir.join("config");
+            if legacy.is_file() {
+                return Some(legacy);
+            }
+            Some(dir.join("config.toml")).filter(|path| path.is_file())
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn runner_of_triple_is_used() {
+        let dir = TempDir::new("cargo-profile-runner").unwrap();
+        let crate_dir = dir.path().join("crate");
+        fs::create_dir_all(crate_dir.join(".cargo")).unwrap();
+        fs::create_dir_all(dir.path().join(".cargo")).unwrap();
+        fs::write(
+            crate_dir.join(".cargo").join("config.toml"),
+            "[target.x86_64-unknown-linux-musl]\nrunner = \"taskset -c 2\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join(".cargo").join("config.toml"),
+            "[target.aarch64-unknown-linux-gnu]\nrunner = [\"tools/qemu\", \"-L\", \"/sysroot\"]\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            runner("x86_64-unknown-linux-musl", &crate_dir).unwrap(),
+            vec!["taskset", "-c", "2"]
+        );
+        assert_eq!(
+            runner("aarch64-unknown-linux-gnu", &crate_dir).unwrap(),
+            vec![
+                dir.path().join("tools/qemu").to_string_lossy().into_owned(),
+                "-L".into(),
+                "/sysroot".into()
+            ]
+        );
+        assert_eq!(
+            runner("riscv64gc-unknown-linux-gnu", &crate_dir).unwrap(),
+            Vec::<String>::new()
+        );
+    }
+}
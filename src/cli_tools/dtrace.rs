@@ -1,14 +1,27 @@
+use super::symbols::Dso;
 use crate::cargo::BinFile;
+use crate::symbolize::Symbolizer;
 use crate::util::command;
+use crate::util::for_each_line;
 use anyhow::Context;
 use anyhow::Error;
 use inferno::collapse::dtrace::Folder;
 use inferno::collapse::dtrace::Options as CollapseOptions;
 use inferno::collapse::Collapse;
-use std::fs::OpenOptions;
+use rustc_demangle::demangle;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io;
+use std::io::BufRead;
 use std::io::BufReader;
+use std::io::Read;
+use std::mem;
 use std::path::Path;
 use std::process::Command;
+use std::time::Duration;
+
+pub(crate) const DTRACE_OUTPUT_FILENAME: &str = "cargo-profile.stacks";
 
 pub(crate) fn make_dtrace_command(
     root: bool,
@@ -35,6 +48,10 @@ pub(crate) fn make_dtrace_command(
     c.arg(output);
 
     let mut escaped = String::new();
+    for arg in &file.runner {
+        escaped.push_str(&arg.replace(" ", "\\ "));
+        escaped.push(' ');
+    }
     escaped.push_str(&file.path.to_string_lossy());
     for arg in args {
         escaped.push(' ');
@@ -50,31 +67,339 @@ pub(crate) fn make_dtrace_command(
     Ok(c)
 }
 
-pub(crate) fn to_collapsed(stacks_file: &Path) -> Result<Vec<u8>, Error> {
-    let output = OpenOptions::new()
-        .read(true)
-        .write(false)
-        .open(stacks_file)
-        .with_context(|| {
-            format!(
-                "failed to open stacks file ({}) generated by dtrace",
-                stacks_file.display()
-            )
-        })?;
-    let perf_reader = BufReader::new(&output);
+/// Attaches to a running process.
+pub(crate) fn make_dtrace_attach_command(
+    root: bool,
+    pid: u32,
+    output: &Path,
+    freq: Option<u32>,
+    duration: Option<Duration>,
+) -> Result<Command, Error> {
+    let mut c = command(root, "dtrace");
+
+    let mut dtrace_script = format!(
+        "profile-{} /pid == $target/ {{ @[ustack(100)] = count(); }}",
+        freq.unwrap_or(997)
+    );
+    if let Some(duration) = duration {
+        dtrace_script.push_str(&format!(
+            " tick-{}ms {{ exit(0); }}",
+            duration.as_millis().max(1)
+        ));
+    }
+
+    c.arg("-x");
+    c.arg("ustackframes=100");
+
+    c.arg("-n");
+    c.arg(&dtrace_script);
+
+    c.arg("-o");
+    c.arg(output);
+
+    c.arg("-p");
+    c.arg(pid.to_string());
+
+    Ok(c)
+}
+
+/// Collapses stacks recorded by dtrace.
+///
+/// Frames of `binaries` are expanded to inlined functions if their debuginfo
+/// is available. The file is streamed, so it's not kept in memory.
+pub(crate) fn to_collapsed(stacks_file: &Path, binaries: &[BinFile]) -> Result<Vec<u8>, Error> {
+    let open = || {
+        File::open(stacks_file)
+            .map(BufReader::new)
+            .with_context(|| {
+                format!(
+                    "failed to open stacks file ({}) generated by dtrace",
+                    stacks_file.display()
+                )
+            })
+    };
 
     let mut collapsed = vec![];
 
     let collapse_options = CollapseOptions::default();
+    let mut folder = Folder::from(collapse_options);
+
+    let result = if binaries.is_empty() {
+        folder.collapse(open()?, &mut collapsed)
+    } else {
+        // Addresses are resolved before stacks are collapsed, so debuginfo is
+        // read by many threads.
+        let mut frames = Frames::new(binaries);
+        let mut addresses = HashSet::new();
+        for_each_line(open()?, |line| {
+            if let Some((binary, _, address)) = frames.resolve(line) {
+                addresses.insert((binary, address));
+            }
+        })
+        .with_context(|| format!("failed to read {}", stacks_file.display()))?;
+
+        let mut symbolizer = Symbolizer::new(binaries);
+        symbolizer.symbolize_all(addresses);
 
-    Folder::from(collapse_options)
-        .collapse(perf_reader, &mut collapsed)
-        .with_context(|| {
-            format!(
-                "unable to collapse generated profile data from {}",
-                stacks_file.display()
-            )
-        })?;
+        let result = folder.collapse(
+            InlinedStacks {
+                reader: open()?,
+                frames: Frames::new(binaries),
+                symbolizer: &mut symbolizer,
+                line: vec![],
+                buf: String::new(),
+                pos: 0,
+            },
+            &mut collapsed,
+        );
+        symbolizer.save();
+        result
+    };
+    result.with_context(|| {
+        format!(
+            "unable to collapse generated profile data from {}",
+            stacks_file.display()
+        )
+    })?;
 
     Ok(collapsed)
 }
+
+/// Finds addresses of frames of binaries in dtrace stacks.
+struct Frames<'a> {
+    binaries: &'a [BinFile],
+    /// Addresses of functions by names printed by dtrace, by binaries.
+    functions: HashMap<&'a Path, Option<HashMap<String, u64>>>,
+    is_leaf: bool,
+}
+
+impl<'a> Frames<'a> {
+    fn new(binaries: &'a [BinFile]) -> Self {
+        Frames {
+            binaries,
+            functions: Default::default(),
+            is_leaf: true,
+        }
+    }
+
+    /// Returns the binary, the module and the address of a frame like
+    /// `simple`simple::main::h0123+0x20`.
+    ///
+    /// Lines should be passed in order, as callers are at return addresses,
+    /// which may point to the next line.
+    fn resolve<'l>(&mut self, line: &'l str) -> Option<(&'a Path, &'l str, u64)> {
+        let frame = line.trim();
+        // Stacks are separated by counts.
+        if frame.is_empty() || frame.bytes().all(|b| b.is_ascii_digit()) {
+            self.is_leaf = true;
+            return None;
+        }
+        let is_leaf = mem::replace(&mut self.is_leaf, false);
+
+        let (module, func, offset) = parse_frame(frame)?;
+        let binary = self
+            .binaries
+            .iter()
+            .find(|b| b.path.file_name() == Some(module.as_ref()))?;
+        let address = self
+            .functions
+            .entry(&binary.path)
+            .or_insert_with(|| {
+                Dso::load(&binary.path)
+                    .ok()
+                    .map(|dso| index_functions(&dso))
+            })
+            .as_ref()?
+            .get(&unescape(func))?
+            + offset;
+
+        Some((
+            &binary.path,
+            module,
+            if is_leaf { address } else { address - 1 },
+        ))
+    }
+}
+
+/// Lines of dtrace stacks, where frames of binaries like
+/// `simple`simple::main::h0123+0x20` are replaced with lines of the function
+/// and functions inlined into it at the address, from the innermost one, as
+/// stacks are printed from the leaf.
+///
+/// Replaced frames have mangled names, which are demangled while normalizing
+/// stacks.
+struct InlinedStacks<'a, R> {
+    reader: R,
+    frames: Frames<'a>,
+    symbolizer: &'a mut Symbolizer,
+    line: Vec<u8>,
+    /// Lines replacing `line`.
+    buf: String,
+    /// Bytes of `buf` already read.
+    pos: usize,
+}
+
+impl<R: BufRead> Read for InlinedStacks<'_, R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        let buf = self.fill_buf()?;
+        let len = buf.len().min(out.len());
+        out[..len].copy_from_slice(&buf[..len]);
+        self.consume(len);
+
+        Ok(len)
+    }
+}
+
+impl<R: BufRead> BufRead for InlinedStacks<'_, R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+
+            self.line.clear();
+            if self.reader.read_until(b'\n', &mut self.line)? == 0 {
+                break;
+            }
+            let line = String::from_utf8_lossy(&self.line);
+            let line = line.trim_end_matches(&['\n', '\r'][..]);
+
+            let locations = match self.frames.resolve(line) {
+                Some((binary, module, address)) => {
+                    Some((module, self.symbolizer.symbolize(binary, address)))
+                }
+                None => None,
+            };
+            match locations {
+                Some((module, locations)) if !locations.is_empty() => {
+                    let indent = &line[..line.len() - line.trim_start().len()];
+                    for location in locations.iter().rev() {
+                        self.buf.push_str(indent);
+                        self.buf.push_str(module);
+                        self.buf.push('`');
+                        self.buf.push_str(&location.name);
+                        self.buf.push('\n');
+                    }
+                }
+                _ => {
+                    self.buf.push_str(line);
+                    self.buf.push('\n');
+                }
+            }
+        }
+
+        Ok(&self.buf.as_bytes()[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+/// Parses `module`function+0x20` into the module, the function and the
+/// offset.
+fn parse_frame(frame: &str) -> Option<(&str, &str, u64)> {
+    let (module, rest) = frame.split_once('`')?;
+    let (func, offset) = match rest.rsplit_once("+0x") {
+        Some((func, offset)) => (func, u64::from_str_radix(offset, 16).ok()?),
+        None => (rest, 0),
+    };
+
+    Some((module, func, offset))
+}
+
+/// Returns addresses of functions by names printed by dtrace.
+///
+/// dtrace demangles legacy rust symbols like C++ symbols, which keeps hashes
+/// and escapes like `$LT$`. Symbols of Mach-O files have a leading underscore,
+/// which is not printed.
+fn index_functions(dso: &Dso) -> HashMap<String, u64> {
+    let mut functions = HashMap::new();
+    for (address, name) in dso.functions() {
+        let name = name.strip_prefix('_').unwrap_or(name);
+        functions.insert(name.to_string(), address);
+        functions.insert(demangle(name).to_string(), address);
+    }
+    functions
+}
+
+/// Replaces escapes of legacy rust symbols, like `rustc-demangle` does.
+fn unescape(func: &str) -> String {
+    const ESCAPES: &[(&str, &str)] = &[
+        ("$SP$", "@"),
+        ("$BP$", "*"),
+        ("$RF$", "&"),
+        ("$LT$", "<"),
+        ("$GT$", ">"),
+        ("$LP$", "("),
+        ("$RP$", ")"),
+        ("$C$", ","),
+        ("$u20$", " "),
+        ("$u27$", "'"),
+        ("$u5b$", "["),
+        ("$u5d$", "]"),
+        ("$u7b$", "{"),
+        ("$u7d$", "}"),
+        ("$u7e$", "~"),
+        ("..", "::"),
+    ];
+
+    if !func.contains('$') && !func.contains("..") {
+        return func.to_string();
+    }
+
+    // Components starting with an escape are prefixed with `_`.
+    let mut func = func.replace("::_$", "::$");
+    if func.starts_with("_$") {
+        func.remove(0);
+    }
+    for (from, to) in ESCAPES {
+        func = func.replace(from, to);
+    }
+    func
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_are_parsed() {
+        assert_eq!(
+            parse_frame("simple`simple::main::h0123456789abcdef+0x2a"),
+            Some(("simple", "simple::main::h0123456789abcdef", 0x2a))
+        );
+        assert_eq!(
+            parse_frame("libsystem_kernel.dylib`__psynch_cvwait"),
+            Some(("libsystem_kernel.dylib", "__psynch_cvwait", 0))
+        );
+        assert_eq!(parse_frame("0x10234abcd"), None);
+    }
+
+    #[test]
+    fn unknown_frames_are_kept() {
+        let stacks =
+            "\n              libc`foo+0x12\n              a.out`main+0x10\n               3\n";
+
+        let mut out = String::new();
+        InlinedStacks {
+            reader: stacks.as_bytes(),
+            frames: Frames::new(&[]),
+            symbolizer: &mut Symbolizer::default(),
+            line: vec![],
+            buf: String::new(),
+            pos: 0,
+        }
+        .read_to_string(&mut out)
+        .unwrap();
+
+        assert_eq!(out, stacks);
+    }
+
+    #[test]
+    fn escapes_are_replaced() {
+        assert_eq!(
+            unescape("_$LT$alloc..vec..Vec$LT$T$GT$$u20$as$u20$core..ops..drop..Drop$GT$::drop"),
+            "<alloc::vec::Vec<T> as core::ops::drop::Drop>::drop"
+        );
+    }
+}
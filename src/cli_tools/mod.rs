@@ -1,2 +1,6 @@
 pub mod dtrace;
+pub mod perf;
+mod perf_data;
 pub mod profiler;
+pub mod recording;
+mod symbols;
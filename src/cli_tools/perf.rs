@@ -0,0 +1,603 @@
+use super::perf_data;
+use super::perf_data::Record;
+use crate::cargo::BinFile;
+use crate::cli_tools::profiler::RecordOptions;
+use crate::stacks::offcpu::SCHED_SWITCH;
+use crate::stacks::ContextSwitch;
+use crate::stacks::Frame;
+use crate::stacks::Sample;
+use crate::stacks::StackCounts;
+use crate::symbolize::Symbolizer;
+use crate::util::command;
+use crate::util::for_each_line;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Error;
+use inferno::collapse::perf::Folder;
+use inferno::collapse::perf::Options as CollapseOptions;
+use inferno::collapse::Collapse;
+use std::env;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::path::Path;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+pub(crate) const PERF_OUTPUT_FILENAME: &str = "perf.data";
+
+/// Invoked perf to record cpu usages.
+///
+/// Recorded data is written to `output`.
+pub(crate) fn make_perf_command(
+    root: bool,
+    file: &BinFile,
+    output: &Path,
+    opts: &RecordOptions,
+    args: &[String],
+) -> Result<Command, Error> {
+    let mut c = perf_record(root, output, opts)?;
+
+    // perf follows the runner to the binary.
+    c.args(&file.runner);
+    c.arg(&file.path);
+    if file.is_bench {
+        c.arg("--bench");
+    }
+
+    c.args(args);
+
+    Ok(c)
+}
+
+/// Attaches perf to a running process.
+pub(crate) fn make_perf_attach_command(
+    root: bool,
+    pid: u32,
+    output: &Path,
+    opts: &RecordOptions,
+    duration: Option<Duration>,
+) -> Result<Command, Error> {
+    let mut c = perf_record(root, output, opts)?;
+
+    c.arg("-p").arg(pid.to_string());
+
+    // perf records until the command exits.
+    if let Some(duration) = duration {
+        c.arg("--")
+            .arg("sleep")
+            .arg(format!("{}", duration.as_secs_f64()));
+    }
+
+    Ok(c)
+}
+
+fn perf_record(root: bool, output: &Path, opts: &RecordOptions) -> Result<Command, Error> {
+    let perf = env::var("PERF").unwrap_or_else(|_| "perf".to_string());
+
+    let mut c = command(root, &perf);
+
+    c.arg("record")
+        .arg("-F")
+        .arg(format!("{}", opts.freq.unwrap_or(997)))
+        .arg("--call-graph")
+        .arg(opts.perf_call_graph()?);
+
+    if let Some(event) = &opts.event {
+        c.arg("-e").arg(event);
+    } else if opts.sched_switch {
+        // perf does not record the default event if any event is specified.
+        c.arg("-e").arg("task-clock");
+    }
+
+    if opts.sched_switch {
+        // Every switch is recorded, regardless of `-F`.
+        c.arg("-e").arg(format!("{}/period=1/", SCHED_SWITCH));
+    }
+
+    if opts.switch_events {
+        c.arg("--switch-events");
+    }
+
+    c.arg("-o").arg(output);
+
+    Ok(c)
+}
+
+/// Gives `perf.data` recorded with sudo to the current user, so it can be read
+/// without `perf script`.
+pub(crate) fn chown_to_user(perf_data: &Path) -> Result<(), Error> {
+    let id = |flag: &str| -> Result<String, Error> {
+        let output = Command::new("id")
+            .arg(flag)
+            .output()
+            .context("failed to run `id`")?;
+        if !output.status.success() {
+            bail!("`id {}` failed", flag)
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+    let owner = format!("{}:{}", id("-u")?, id("-g")?);
+
+    let status = command(true, "chown")
+        .arg(&owner)
+        .arg(perf_data)
+        .status()
+        .context("failed to run `sudo chown`")?;
+    if !status.success() {
+        bail!("`sudo chown {} {}` failed", owner, perf_data.display())
+    }
+
+    Ok(())
+}
+
+/// Runs `perf script` with `args`, and passes the output to `read` while perf
+/// writes it, so the output is not kept in memory.
+fn perf_script<T>(
+    root: bool,
+    perf_data: &Path,
+    args: &[&str],
+    read: impl FnOnce(&mut dyn BufRead) -> Result<T, Error>,
+) -> Result<T, Error> {
+    let perf = env::var("PERF").unwrap_or_else(|_| "perf".to_string());
+
+    // The data file is owned by root if it's recorded with sudo.
+    let mut child = command(root, &perf)
+        .arg("script")
+        .arg("-i")
+        .arg(perf_data)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to run `perf script -i {}`", perf_data.display()))?;
+
+    // Read by another thread, as perf blocks if the pipe is full.
+    let mut stderr = child.stderr.take().expect("stderr of perf is piped");
+    let stderr = thread::spawn(move || {
+        let mut buf = vec![];
+        let _ = stderr.read_to_end(&mut buf);
+        buf
+    });
+
+    let stdout = child.stdout.take().expect("stdout of perf is piped");
+    let result = read(&mut BufReader::new(stdout));
+    if result.is_err() {
+        // The rest of the output is not needed.
+        let _ = child.kill();
+    }
+
+    let status = child.wait().with_context(|| {
+        format!(
+            "failed to wait for `perf script -i {}`",
+            perf_data.display()
+        )
+    })?;
+    let stderr = stderr.join().unwrap_or_default();
+    if result.is_ok() && !status.success() {
+        bail!(
+            "`perf script -i {}` failed: {}",
+            perf_data.display(),
+            String::from_utf8_lossy(&stderr).trim()
+        )
+    }
+
+    result
+}
+
+/// Reads `perf.data` using [perf_data::read], or returns [None] if `perf
+/// script` should be used instead.
+///
+/// `perf.data` recorded with sudo is given to the user by [chown_to_user], but
+/// files stored by previous runs may still be owned by root.
+fn read_natively<T>(perf_data: &Path, read: impl FnOnce() -> Result<T, Error>) -> Option<T> {
+    match read() {
+        Ok(v) => Some(v),
+        Err(err) => {
+            eprintln!(
+                "Failed to read {}, so `perf script` is used instead: {:#}",
+                perf_data.display(),
+                err
+            );
+            None
+        }
+    }
+}
+
+/// Collapses stacks of `perf.data`. The root frame of each stack is the name
+/// of the thread.
+///
+/// If `threads` is true, the root frame is `{name}-{pid}/{tid}` instead.
+///
+/// Debuginfo of `binaries` is used to find inlined functions.
+pub(crate) fn to_collapsed(
+    root: bool,
+    perf_data: &Path,
+    binaries: &[BinFile],
+    threads: bool,
+) -> Result<Vec<u8>, Error> {
+    if let Some(collapsed) = read_natively(perf_data, || {
+        collapse_natively(perf_data, binaries, threads)
+    }) {
+        return Ok(collapsed);
+    }
+
+    perf_script(root, perf_data, &[], |input| {
+        let mut collapsed = vec![];
+
+        let mut collapse_options = CollapseOptions::default();
+        collapse_options.include_tid = threads;
+
+        Folder::from(collapse_options)
+            .collapse(input, &mut collapsed)
+            .context("unable to collapse generated profile data")?;
+
+        Ok(collapsed)
+    })
+}
+
+/// Same as collapsing the output of `perf script`, but samples are not
+/// buffered.
+///
+/// Like inferno, only samples of the first event are collapsed.
+fn collapse_natively(
+    perf_data: &Path,
+    binaries: &[BinFile],
+    threads: bool,
+) -> Result<Vec<u8>, Error> {
+    let mut first_event = None;
+    let mut counts = StackCounts::default();
+
+    let mut symbolizer = Symbolizer::new(binaries);
+    perf_data::read(perf_data, Some(&mut symbolizer), |record| {
+        let sample = match record {
+            Record::Sample(sample) => sample,
+            Record::Switch(_) => return,
+        };
+        if *first_event.get_or_insert_with(|| sample.event.clone()) != sample.event {
+            return;
+        }
+
+        counts.add_sample(&sample, threads);
+    })?;
+    symbolizer.save();
+
+    Ok(counts.into_collapsed())
+}
+
+/// Reads samples with timestamps and threads from `perf.data`.
+///
+/// Frames have source files and lines if debuginfo of `binaries` or shared
+/// libraries is available. If `perf script` is used, they are read only if
+/// `source_lines` is true, which is slow.
+pub(crate) fn read_samples(
+    root: bool,
+    perf_data: &Path,
+    binaries: &[BinFile],
+    source_lines: bool,
+) -> Result<Vec<Sample>, Error> {
+    if let Some(samples) = read_natively(perf_data, || {
+        let mut samples = vec![];
+        let mut symbolizer = Symbolizer::new(binaries);
+        perf_data::read(perf_data, Some(&mut symbolizer), |record| {
+            if let Record::Sample(sample) = record {
+                samples.push(sample);
+            }
+        })?;
+        symbolizer.save();
+        Ok(samples)
+    }) {
+        return Ok(samples);
+    }
+
+    let mut fields = String::from("comm,pid,tid,time,event,period,ip,sym,dso");
+    if source_lines {
+        fields.push_str(",srcline");
+    }
+
+    perf_script(root, perf_data, &["-F", &fields], parse_script)
+}
+
+/// Parses the output of `perf script`.
+///
+/// Each sample is a header line like `comm pid/tid [cpu] time: period event:`
+/// followed by indented frames, from the leaf to the root. A frame may be
+/// followed by a line like `  src/main.rs:10` if `srcline` is requested.
+fn parse_script(s: &mut dyn BufRead) -> Result<Vec<Sample>, Error> {
+    let mut samples = vec![];
+    let mut current: Option<Sample> = None;
+    // Event names are shared, as they are mostly same.
+    let mut events = Vec::<Arc<str>>::new();
+
+    for_each_line(s, |line| {
+        if line.trim().is_empty() {
+            return;
+        }
+
+        if !line.starts_with(char::is_whitespace) {
+            samples.extend(current.take());
+            current = parse_header(line).map(|(mut sample, event)| {
+                sample.event = event.map(|event| match events.iter().find(|e| ***e == *event) {
+                    Some(e) => e.clone(),
+                    None => {
+                        let e = Arc::<str>::from(event);
+                        events.push(e.clone());
+                        e
+                    }
+                });
+                sample
+            });
+            return;
+        }
+
+        if let Some(sample) = &mut current {
+            if let Some(frame) = parse_frame(line) {
+                sample.stack.push(frame);
+            } else if let (Some(frame), Some((file, line))) =
+                (sample.stack.last_mut(), parse_srcline(line))
+            {
+                frame.file = Some(file.to_string());
+                frame.line = Some(line);
+            }
+        }
+    })
+    .context("failed to read the output of `perf script`")?;
+    samples.extend(current);
+
+    for sample in &mut samples {
+        sample.stack.reverse();
+    }
+
+    Ok(samples)
+}
+
+/// Reads context switches recorded using `--switch-events`.
+pub(crate) fn read_context_switches(
+    root: bool,
+    perf_data: &Path,
+) -> Result<Vec<ContextSwitch>, Error> {
+    if let Some(switches) = read_natively(perf_data, || {
+        let mut switches = vec![];
+        perf_data::read(perf_data, None, |record| {
+            if let Record::Switch(switch) = record {
+                switches.push(switch);
+            }
+        })?;
+        Ok(switches)
+    }) {
+        return Ok(switches);
+    }
+
+    perf_script(
+        root,
+        perf_data,
+        &["--show-switch-events", "-F", "comm,pid,tid,time"],
+        parse_context_switches,
+    )
+}
+
+/// Parses lines like `comm pid/tid time: PERF_RECORD_SWITCH OUT preempt`.
+fn parse_context_switches(s: &mut dyn BufRead) -> Result<Vec<ContextSwitch>, Error> {
+    let mut switches = vec![];
+
+    for_each_line(s, |line| {
+        if !line.contains(" PERF_RECORD_SWITCH") {
+            return;
+        }
+        switches.extend(parse_context_switch(line));
+    })
+    .context("failed to read the output of `perf script`")?;
+
+    Ok(switches)
+}
+
+fn parse_context_switch(line: &str) -> Option<ContextSwitch> {
+    let (sample, _) = parse_header(line)?;
+    // `_CPU_WIDE OUT preempt next pid/tid: 0/0` if perf recorded all cpus.
+    let (_, record) = line.split_once(" PERF_RECORD_SWITCH")?;
+    let out = record.split_whitespace().any(|token| token == "OUT");
+    let preempted = out && record.split_whitespace().any(|token| token == "preempt");
+
+    Some(ContextSwitch {
+        pid: sample.pid,
+        tid: sample.tid,
+        time: sample.time,
+        out,
+        preempted,
+    })
+}
+
+/// Returns the sample and the name of the event.
+fn parse_header(line: &str) -> Option<(Sample, Option<&str>)> {
+    let tokens = line.split_whitespace().collect::<Vec<_>>();
+
+    let time_idx = tokens.iter().position(|token| {
+        token.ends_with(':') && token[..token.len() - 1].parse::<f64>().is_ok()
+    })?;
+    let time = tokens[time_idx].trim_end_matches(':').parse().ok()?;
+
+    // Skip `[cpu]`.
+    let ids_idx = tokens[..time_idx]
+        .iter()
+        .rposition(|token| !token.starts_with('['))?;
+    let (pid, tid) = match tokens[ids_idx].split_once('/') {
+        Some((pid, tid)) => (pid.parse().ok()?, tid.parse().ok()?),
+        None => {
+            let tid = tokens[ids_idx].parse().ok()?;
+            (tid, tid)
+        }
+    };
+
+    let mut rest = &tokens[time_idx + 1..];
+    let period = match rest.first().and_then(|token| token.parse().ok()) {
+        Some(period) => {
+            rest = &rest[1..];
+            period
+        }
+        None => 1,
+    };
+    // `cycles:u:` => `cycles:u`
+    let event = rest
+        .first()
+        .filter(|token| token.ends_with(':'))
+        .map(|token| &token[..token.len() - 1]);
+
+    let sample = Sample {
+        comm: tokens[..ids_idx].join(" "),
+        pid,
+        tid,
+        time,
+        event: None,
+        period,
+        stack: vec![],
+    };
+
+    Some((sample, event))
+}
+
+/// Parses a line like `55d0c0a0 simple::main+0x10 (/tmp/simple)`.
+fn parse_frame(line: &str) -> Option<Frame> {
+    let (addr, rest) = line.trim().split_once(' ')?;
+    let address = u64::from_str_radix(addr, 16).ok()?;
+
+    let (sym, module) = match rest.rsplit_once(" (") {
+        Some((sym, module)) => (sym.trim(), module.trim_end_matches(')')),
+        None => (rest.trim(), ""),
+    };
+    let module = if module.is_empty() || module == "[unknown]" {
+        None
+    } else {
+        Some(module)
+    };
+
+    // Strip offsets like `+0x10`.
+    let sym = match sym.rsplit_once("+0x") {
+        Some((sym, offset)) if offset.bytes().all(|b| b.is_ascii_hexdigit()) => sym,
+        _ => sym,
+    };
+
+    let name = match module {
+        Some(module) if sym == "[unknown]" => {
+            format!("[{}]", module.rsplit('/').next().unwrap_or(module))
+        }
+        _ => sym.to_string(),
+    };
+
+    Some(Frame {
+        name,
+        module: module.map(String::from),
+        address: Some(address),
+        file: None,
+        line: None,
+    })
+}
+
+/// Parses a line like `  src/main.rs:10`. `??:0` is ignored.
+fn parse_srcline(line: &str) -> Option<(&str, u32)> {
+    let (file, line) = line.trim().rsplit_once(':')?;
+    let line = line.parse().ok()?;
+    if file == "??" || line == 0 {
+        return None;
+    }
+
+    Some((file, line))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame(name: &str, module: &str, address: u64) -> Frame {
+        Frame {
+            name: name.into(),
+            module: Some(module.into()),
+            address: Some(address),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn context_switches_are_parsed() {
+        let switches = parse_context_switches(
+            &mut "simple 1234/1235 [001] 10.500000: PERF_RECORD_SWITCH OUT preempt
+simple 1234/1235 [001] 10.500000: 
+simple 1234/1235 [002] 10.700000: PERF_RECORD_SWITCH IN
+"
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            switches,
+            vec![
+                ContextSwitch {
+                    pid: 1234,
+                    tid: 1235,
+                    time: 10.5,
+                    out: true,
+                    preempted: true,
+                },
+                ContextSwitch {
+                    pid: 1234,
+                    tid: 1235,
+                    time: 10.7,
+                    out: false,
+                    preempted: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn script_is_parsed() {
+        let samples = parse_script(
+            &mut "worker 1 1234/1235 [003] 10.500000: 
+\t    55d0c0c0 simple::foo+0x10 (/tmp/simple)
+\t    55d0c0a0 simple::main (/tmp/simple)
+  src/main.rs:10
+\t    7f00c0a0 [unknown] (/usr/lib/libc.so.6)
+  ??:0
+
+simple 1234 10.750000:     250000 cpu-clock:u: 
+\t    55d0c0a0 simple::main+0x10 (/tmp/simple)
+"
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(
+            samples,
+            vec![
+                Sample {
+                    comm: "worker 1".into(),
+                    pid: 1234,
+                    tid: 1235,
+                    time: 10.5,
+                    event: None,
+                    period: 1,
+                    stack: vec![
+                        frame("[libc.so.6]", "/usr/lib/libc.so.6", 0x7f00c0a0),
+                        Frame {
+                            file: Some("src/main.rs".into()),
+                            line: Some(10),
+                            ..frame("simple::main", "/tmp/simple", 0x55d0c0a0)
+                        },
+                        frame("simple::foo", "/tmp/simple", 0x55d0c0c0),
+                    ],
+                },
+                Sample {
+                    comm: "simple".into(),
+                    pid: 1234,
+                    tid: 1234,
+                    time: 10.75,
+                    event: Some("cpu-clock:u".into()),
+                    period: 250000,
+                    stack: vec![frame("simple::main", "/tmp/simple", 0x55d0c0a0)],
+                },
+            ]
+        );
+    }
+}
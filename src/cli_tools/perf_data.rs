@@ -0,0 +1,710 @@
+//! Reads `perf.data` directly, instead of parsing the output of `perf script`.
+//!
+//! Records are streamed, so only the state of processes (names of threads and
+//! mapped binaries) is kept in memory.
+
+use super::symbols::Dso;
+use super::symbols::FileRange;
+use super::symbols::KernelSymbols;
+use crate::stacks::ContextSwitch;
+use crate::stacks::Frame;
+use crate::stacks::Sample;
+use crate::symbolize::Symbolizer;
+use anyhow::Context;
+use anyhow::Error;
+use linux_perf_data::linux_perf_event_reader::constants::PERF_CONTEXT_KERNEL;
+use linux_perf_data::linux_perf_event_reader::constants::PERF_CONTEXT_MAX;
+use linux_perf_data::linux_perf_event_reader::constants::PERF_CONTEXT_USER;
+use linux_perf_data::linux_perf_event_reader::CommOrExecRecord;
+use linux_perf_data::linux_perf_event_reader::ContextSwitchRecord;
+use linux_perf_data::linux_perf_event_reader::CpuMode;
+use linux_perf_data::linux_perf_event_reader::EventRecord;
+use linux_perf_data::linux_perf_event_reader::ForkOrExitRecord;
+use linux_perf_data::linux_perf_event_reader::SampleRecord;
+use linux_perf_data::linux_perf_event_reader::TaskWasPreempted;
+use linux_perf_data::PerfFileReader;
+use linux_perf_data::PerfFileRecord;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Kernel addresses are in the upper half of the address space.
+const KERNEL_START: u64 = 0xffff_0000_0000_0000;
+
+/// Stacks deeper than this are truncated while unwinding.
+const MAX_DEPTH: usize = 1024;
+
+/// Records are symbolized in batches of this size, so debuginfo is read by
+/// many threads while memory usage is bounded.
+const BATCH_SIZE: usize = 8192;
+
+/// A record of `perf.data`.
+pub(crate) enum Record {
+    Sample(Sample),
+    Switch(ContextSwitch),
+}
+
+/// A record waiting for its batch to be symbolized.
+enum Pending {
+    /// Frames named using symbol tables, and binaries and relative addresses
+    /// of frames which may have inlined functions, from the root to the leaf.
+    Sample(Sample, Vec<Option<(String, u64)>>),
+    Switch(ContextSwitch),
+}
+
+/// Reads samples and context switches of `perf.data`, in the order of
+/// timestamps, and passes them to `f`.
+///
+/// Stacks of samples are unwound using `.eh_frame` of binaries if user stacks
+/// are recorded, which is the case for `--call-graph dwarf`. Frames are named
+/// using `symbolizer`, or symbol tables of binaries and `/proc/kallsyms`.
+/// Inlined functions and source lines are resolved only by `symbolizer`, for
+/// a batch of records at once.
+pub(crate) fn read(
+    perf_data: &Path,
+    mut symbolizer: Option<&mut Symbolizer>,
+    mut f: impl FnMut(Record),
+) -> Result<(), Error> {
+    let file =
+        File::open(perf_data).with_context(|| format!("failed to open {}", perf_data.display()))?;
+    let PerfFileReader {
+        mut perf_file,
+        mut record_iter,
+    } = PerfFileReader::parse_file(BufReader::new(file))
+        .with_context(|| format!("failed to parse {}", perf_data.display()))?;
+
+    let events = perf_file
+        .event_attributes()
+        .iter()
+        .map(|attr| attr.name().map(event_name))
+        .collect::<Vec<_>>();
+
+    let mut processes = Processes::default();
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+
+    while let Some(record) = record_iter.next_record(&mut perf_file)? {
+        let (attr_index, record) = match record {
+            PerfFileRecord::EventRecord { attr_index, record } => (attr_index, record),
+            PerfFileRecord::UserRecord(_) => continue,
+        };
+
+        match record.parse()? {
+            EventRecord::Sample(sample) => {
+                let event = events.get(attr_index).cloned().flatten();
+                let (sample, lookups) = processes.sample(&sample, event);
+                batch.push(Pending::Sample(sample, lookups));
+            }
+            EventRecord::Comm(comm) => processes.comm(&comm),
+            EventRecord::Fork(fork) => processes.fork(&fork),
+            EventRecord::Mmap(mmap) => processes.mmap(
+                mmap.pid,
+                mmap.address,
+                mmap.length,
+                mmap.page_offset,
+                &mmap.path.as_slice(),
+            ),
+            EventRecord::Mmap2(mmap) => processes.mmap(
+                mmap.pid,
+                mmap.address,
+                mmap.length,
+                mmap.page_offset,
+                &mmap.path.as_slice(),
+            ),
+            EventRecord::ContextSwitch(switch) => {
+                let common = record.common_data()?;
+                let (out, preempted) = match switch {
+                    ContextSwitchRecord::In { .. } => (false, false),
+                    ContextSwitchRecord::Out { preempted, .. } => {
+                        (true, preempted == TaskWasPreempted::Yes)
+                    }
+                };
+
+                batch.push(Pending::Switch(ContextSwitch {
+                    pid: common.pid.unwrap_or(0) as u32,
+                    tid: common.tid.unwrap_or(0) as u32,
+                    time: common.timestamp.unwrap_or(0) as f64 / 1e9,
+                    out,
+                    preempted,
+                }));
+            }
+            _ => {}
+        }
+
+        if batch.len() == BATCH_SIZE {
+            flush(&mut batch, symbolizer.as_deref_mut(), &mut f);
+        }
+    }
+    flush(&mut batch, symbolizer, &mut f);
+
+    Ok(())
+}
+
+/// Replaces frames of binaries with functions inlined at their addresses,
+/// and passes records of `batch` to `f`.
+fn flush(
+    batch: &mut Vec<Pending>,
+    mut symbolizer: Option<&mut Symbolizer>,
+    f: &mut impl FnMut(Record),
+) {
+    if let Some(symbolizer) = symbolizer.as_deref_mut() {
+        symbolizer.symbolize_all(
+            batch
+                .iter()
+                .flat_map(|pending| match pending {
+                    Pending::Sample(_, lookups) => &lookups[..],
+                    Pending::Switch(_) => &[],
+                })
+                .flatten()
+                .map(|(path, address)| (Path::new(path), *address)),
+        );
+    }
+
+    for pending in batch.drain(..) {
+        let (sample, lookups) = match pending {
+            Pending::Sample(sample, lookups) => (sample, lookups),
+            Pending::Switch(switch) => {
+                f(Record::Switch(switch));
+                continue;
+            }
+        };
+        let symbolizer = match symbolizer.as_deref_mut() {
+            Some(symbolizer) => symbolizer,
+            None => {
+                f(Record::Sample(sample));
+                continue;
+            }
+        };
+
+        let mut stack = Vec::with_capacity(sample.stack.len());
+        for (frame, lookup) in sample.stack.into_iter().zip(lookups) {
+            let locations = match &lookup {
+                Some((path, address)) => symbolizer.symbolize(Path::new(path), *address),
+                None => &[],
+            };
+            if locations.is_empty() {
+                stack.push(frame);
+                continue;
+            }
+
+            stack.extend(locations.iter().map(|l| Frame {
+                name: l.name.clone(),
+                file: l.file.clone(),
+                line: l.line,
+                ..frame.clone()
+            }));
+        }
+
+        f(Record::Sample(Sample { stack, ..sample }));
+    }
+}
+
+/// Names of events are shared, as they are mostly same.
+///
+/// `sched:sched_switch/period=1/` => `sched:sched_switch`, which is the name
+/// printed by `perf script`.
+fn event_name(name: &str) -> Arc<str> {
+    match name.split_once('/') {
+        Some((tracepoint, _)) if tracepoint.contains(':') => tracepoint.into(),
+        _ => name.into(),
+    }
+}
+
+#[derive(Default)]
+struct Processes {
+    /// Names of threads, by tids.
+    comms: HashMap<i32, String>,
+    /// Mapped binaries, by pids.
+    maps: HashMap<i32, Process>,
+    /// Binaries are loaded once, even if they are mapped by many processes.
+    dsos: HashMap<String, Option<Arc<Dso>>>,
+    kernel: Option<KernelSymbols>,
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    cache: unwind::Cache,
+}
+
+#[derive(Default)]
+struct Process {
+    /// Sorted by addresses.
+    mappings: Vec<Mapping>,
+    /// Created when a stack of the process is unwound, and dropped when a
+    /// binary is mapped.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    unwinder: Option<unwind::Unwinder>,
+}
+
+#[derive(Clone)]
+struct Mapping {
+    start: u64,
+    end: u64,
+    /// Address of the binary, where addresses of symbols start from.
+    base: u64,
+    path: String,
+    dso: Option<Arc<Dso>>,
+}
+
+impl Processes {
+    fn comm(&mut self, comm: &CommOrExecRecord) {
+        let name = String::from_utf8_lossy(&comm.name.as_slice()).into_owned();
+        self.comms.insert(comm.tid, name);
+
+        if comm.is_execve {
+            self.maps.remove(&comm.pid);
+        }
+    }
+
+    fn fork(&mut self, fork: &ForkOrExitRecord) {
+        if let Some(name) = self.comms.get(&fork.ptid).cloned() {
+            self.comms.insert(fork.tid, name);
+        }
+
+        if fork.pid != fork.ppid {
+            if let Some(parent) = self.maps.get(&fork.ppid) {
+                let child = Process {
+                    mappings: parent.mappings.clone(),
+                    ..Default::default()
+                };
+                self.maps.insert(fork.pid, child);
+            }
+        }
+    }
+
+    fn mmap(&mut self, pid: i32, start: u64, len: u64, page_offset: u64, path: &[u8]) {
+        // The kernel and its modules are symbolized using kallsyms.
+        if pid == -1 || len == 0 {
+            return;
+        }
+
+        let path = String::from_utf8_lossy(path).into_owned();
+        let dso = self
+            .dsos
+            .entry(path.clone())
+            .or_insert_with(|| {
+                // Anonymous mappings like `[heap]` or `//anon` are not files.
+                if path.starts_with('/') {
+                    Dso::load(Path::new(&path)).ok().map(Arc::new)
+                } else {
+                    None
+                }
+            })
+            .clone();
+        let base = match &dso {
+            Some(dso) => dso.base_address(start, page_offset),
+            None => start.wrapping_sub(page_offset),
+        };
+
+        let end = start + len;
+        let process = self.maps.entry(pid).or_default();
+        process
+            .mappings
+            .retain(|m| m.end <= start || end <= m.start);
+        let idx = process.mappings.partition_point(|m| m.start < start);
+        process.mappings.insert(
+            idx,
+            Mapping {
+                start,
+                end,
+                base,
+                path,
+                dso,
+            },
+        );
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        {
+            process.unwinder = None;
+        }
+    }
+
+    /// Returns the sample, and binaries and relative addresses of its frames.
+    fn sample(
+        &mut self,
+        sample: &SampleRecord,
+        event: Option<Arc<str>>,
+    ) -> (Sample, Vec<Option<(String, u64)>>) {
+        let pid = sample.pid.unwrap_or(0);
+        let tid = sample.tid.unwrap_or(pid);
+
+        let (mut stack, mut lookups): (Vec<_>, Vec<_>) = self
+            .addresses(pid, sample)
+            .into_iter()
+            .map(|(address, kernel, is_return)| self.frame(pid, address, kernel, is_return))
+            .unzip();
+        stack.reverse();
+        lookups.reverse();
+
+        let sample = Sample {
+            comm: self
+                .comms
+                .get(&tid)
+                .cloned()
+                .unwrap_or_else(|| format!(":{}", tid)),
+            pid: pid as u32,
+            tid: tid as u32,
+            time: sample.timestamp.unwrap_or(0) as f64 / 1e9,
+            event,
+            period: sample.period.unwrap_or(1),
+            stack,
+        };
+
+        (sample, lookups)
+    }
+
+    /// Returns addresses of frames as `(address, kernel, is_return)`, from the
+    /// leaf to the root.
+    ///
+    /// Addresses of callers are return addresses, which may point to the
+    /// next function.
+    fn addresses(&mut self, pid: i32, sample: &SampleRecord) -> Vec<(u64, bool, bool)> {
+        let mut kernel = sample.cpu_mode == CpuMode::Kernel;
+        // The callchain does not contain user frames if user stacks are
+        // recorded.
+        let unwind_user = sample.user_stack.is_some() && sample.user_regs.is_some();
+
+        let mut addresses = vec![];
+        match &sample.callchain {
+            Some(callchain) => {
+                let mut is_return = false;
+                for address in (0..callchain.len()).filter_map(|i| callchain.get(i)) {
+                    if address >= PERF_CONTEXT_MAX {
+                        match address {
+                            PERF_CONTEXT_KERNEL => kernel = true,
+                            PERF_CONTEXT_USER if unwind_user => break,
+                            PERF_CONTEXT_USER => kernel = false,
+                            _ => {}
+                        }
+                        is_return = false;
+                        continue;
+                    }
+
+                    addresses.push((address, kernel || address >= KERNEL_START, is_return));
+                    is_return = true;
+                }
+            }
+            None => {
+                if let Some(ip) = sample.ip {
+                    addresses.push((ip, kernel || ip >= KERNEL_START, false));
+                }
+            }
+        }
+
+        #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+        if unwind_user {
+            let user = match self.maps.get_mut(&pid) {
+                Some(process) => unwind::unwind_user(process, &mut self.cache, sample),
+                None => vec![],
+            };
+            addresses.extend(
+                user.into_iter()
+                    .map(|(address, is_return)| (address, false, is_return)),
+            );
+        }
+
+        addresses
+    }
+
+    /// Returns the frame at `address`, named using symbol tables, and the
+    /// binary and the address relative to it if the frame is in a binary.
+    fn frame(
+        &mut self,
+        pid: i32,
+        address: u64,
+        kernel: bool,
+        is_return: bool,
+    ) -> (Frame, Option<(String, u64)>) {
+        let lookup = if is_return {
+            address.saturating_sub(1)
+        } else {
+            address
+        };
+
+        if kernel {
+            let name = self
+                .kernel
+                .get_or_insert_with(KernelSymbols::load)
+                .symbolize(lookup)
+                .unwrap_or("[kernel.kallsyms]");
+
+            let frame = Frame {
+                name: name.to_string(),
+                module: Some("[kernel.kallsyms]".into()),
+                address: Some(address),
+                ..Default::default()
+            };
+            return (frame, None);
+        }
+
+        let mapping = self.maps.get(&pid).and_then(|process| {
+            let idx = process.mappings.partition_point(|m| m.start <= lookup);
+            process
+                .mappings
+                .get(idx.checked_sub(1)?)
+                .filter(|m| lookup < m.end)
+        });
+
+        let m = match mapping {
+            Some(m) => m,
+            None => {
+                let frame = Frame {
+                    name: "[unknown]".into(),
+                    address: Some(address),
+                    ..Default::default()
+                };
+                return (frame, None);
+            }
+        };
+
+        let name = match m
+            .dso
+            .as_ref()
+            .and_then(|dso| dso.symbolize(lookup.wrapping_sub(m.base)))
+        {
+            Some(name) => name.to_string(),
+            // Same as `perf script`.
+            None if m.path.starts_with('[') => m.path.clone(),
+            None => format!("[{}]", m.path.rsplit('/').next().unwrap_or(&m.path)),
+        };
+
+        let frame = Frame {
+            name,
+            module: Some(m.path.clone()),
+            address: Some(address),
+            ..Default::default()
+        };
+        let lookup = m
+            .dso
+            .as_ref()
+            .map(|_| (m.path.clone(), lookup.wrapping_sub(m.base)));
+
+        (frame, lookup)
+    }
+}
+
+/// Unwinding of user stacks copied by perf.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+mod unwind {
+    use super::FileRange;
+    use super::Process;
+    use super::MAX_DEPTH;
+    use framehop::CacheNative;
+    use framehop::Module;
+    use framehop::MustNotAllocateDuringUnwind;
+    use framehop::Unwinder as _;
+    use framehop::UnwinderNative;
+    use linux_perf_data::linux_perf_event_reader::Regs;
+    use linux_perf_data::linux_perf_event_reader::SampleRecord;
+    use std::convert::TryInto;
+
+    pub(super) type Unwinder = UnwinderNative<FileRange, MustNotAllocateDuringUnwind>;
+    pub(super) type Cache = CacheNative<MustNotAllocateDuringUnwind>;
+
+    /// Returns addresses of user frames as `(address, is_return)`, from the
+    /// leaf to the root.
+    pub(super) fn unwind_user(
+        process: &mut Process,
+        cache: &mut Cache,
+        sample: &SampleRecord,
+    ) -> Vec<(u64, bool)> {
+        let (regs, (stack, dynamic_size)) = match (&sample.user_regs, &sample.user_stack) {
+            (Some(regs), Some(stack)) => (regs, stack),
+            _ => return vec![],
+        };
+        let (pc, sp, regs) = match registers(regs) {
+            Some(regs) => regs,
+            None => return vec![],
+        };
+
+        let stack = stack.as_slice();
+        let stack = &stack[..(*dynamic_size as usize).min(stack.len())];
+        let mut read_stack = |addr: u64| {
+            let offset = addr.checked_sub(sp).ok_or(())? as usize;
+            let bytes = stack
+                .get(offset..offset.checked_add(8).ok_or(())?)
+                .ok_or(())?;
+            Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        let mappings = &process.mappings;
+        let unwinder = process.unwinder.get_or_insert_with(|| {
+            let mut unwinder = Unwinder::new();
+            for m in mappings {
+                if let Some(dso) = &m.dso {
+                    unwinder.add_module(Module::new(
+                        m.path.clone(),
+                        m.start..m.end,
+                        m.base,
+                        dso.unwind_sections(),
+                    ));
+                }
+            }
+            unwinder
+        });
+
+        let mut addresses = vec![];
+        let mut frames = unwinder.iter_frames(pc, regs, cache, &mut read_stack);
+        while let Ok(Some(frame)) = frames.next() {
+            if addresses.len() == MAX_DEPTH {
+                break;
+            }
+            addresses.push((frame.address(), !addresses.is_empty()));
+        }
+        addresses
+    }
+
+    /// Returns the program counter, the stack pointer and registers used to
+    /// unwind.
+    #[cfg(target_arch = "x86_64")]
+    fn registers(regs: &Regs) -> Option<(u64, u64, framehop::UnwindRegsNative)> {
+        // `enum perf_event_x86_regs`
+        let (bp, sp, ip) = (regs.get(6)?, regs.get(7)?, regs.get(8)?);
+
+        Some((ip, sp, framehop::UnwindRegsNative::new(ip, sp, bp)))
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn registers(regs: &Regs) -> Option<(u64, u64, framehop::UnwindRegsNative)> {
+        // `enum perf_event_arm_regs`
+        let (fp, lr, sp, pc) = (regs.get(29)?, regs.get(30)?, regs.get(31)?, regs.get(32)?);
+
+        Some((pc, sp, framehop::UnwindRegsNative::new(lr, sp, fp)))
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::*;
+    use std::fs;
+    use tempdir::TempDir;
+
+    #[inline(never)]
+    fn leaf() {}
+
+    #[inline(never)]
+    fn caller() {
+        leaf()
+    }
+
+    fn u32s(buf: &mut Vec<u8>, values: &[u32]) {
+        values
+            .iter()
+            .for_each(|v| buf.extend_from_slice(&v.to_le_bytes()));
+    }
+
+    fn u64s(buf: &mut Vec<u8>, values: &[u64]) {
+        values
+            .iter()
+            .for_each(|v| buf.extend_from_slice(&v.to_le_bytes()));
+    }
+
+    /// Appends a record with a string padded to 8 bytes.
+    fn record(buf: &mut Vec<u8>, kind: u32, body: &[u8], s: Option<&str>) {
+        let mut body = body.to_vec();
+        if let Some(s) = s {
+            body.extend_from_slice(s.as_bytes());
+            body.resize((body.len() / 8 + 1) * 8, 0);
+        }
+
+        u32s(buf, &[kind]);
+        // PERF_RECORD_MISC_USER
+        buf.extend_from_slice(&2u16.to_le_bytes());
+        buf.extend_from_slice(&(8 + body.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&body);
+    }
+
+    /// Returns `(start, end, offset, path)` of the mapping of the test binary
+    /// containing `addr`.
+    fn mapping_of(addr: u64) -> (u64, u64, u64, String) {
+        let maps = fs::read_to_string("/proc/self/maps").unwrap();
+        maps.lines()
+            .find_map(|line| {
+                let tokens = line.split_whitespace().collect::<Vec<_>>();
+                let (start, end) = tokens[0].split_once('-')?;
+                let start = u64::from_str_radix(start, 16).ok()?;
+                let end = u64::from_str_radix(end, 16).ok()?;
+                if !(start..end).contains(&addr) {
+                    return None;
+                }
+                let offset = u64::from_str_radix(tokens[2], 16).ok()?;
+
+                Some((start, end, offset, tokens[5..].join(" ")))
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn samples_are_symbolized() {
+        caller();
+
+        let leaf = leaf as *const () as u64;
+        let caller = caller as *const () as u64;
+        let (start, end, offset, path) = mapping_of(leaf);
+
+        let mut data = vec![];
+        // PERF_RECORD_COMM
+        let mut body = vec![];
+        u32s(&mut body, &[10, 11]);
+        record(&mut data, 3, &body, Some("worker 1"));
+        // PERF_RECORD_MMAP
+        let mut body = vec![];
+        u32s(&mut body, &[10, 11]);
+        u64s(&mut body, &[start, end - start, offset]);
+        record(&mut data, 1, &body, Some(&path));
+        // PERF_RECORD_SAMPLE with ip, tid, time, period and callchain.
+        let mut body = vec![];
+        u64s(&mut body, &[leaf]);
+        u32s(&mut body, &[10, 11]);
+        u64s(
+            &mut body,
+            &[1_500_000_000, 1000, 3, PERF_CONTEXT_USER, leaf, caller + 1],
+        );
+        record(&mut data, 9, &body, None);
+
+        let mut file = vec![];
+        file.extend_from_slice(b"PERFILE2");
+        // Sizes of the header and an attribute with its ids, and sections of
+        // attributes, data and event types.
+        u64s(&mut file, &[104, 80, 104, 80, 184, data.len() as u64, 0, 0]);
+        // Features
+        u64s(&mut file, &[0; 4]);
+        // `perf_event_attr` of a software event, with ip, tid, time,
+        // callchain and period.
+        u32s(&mut file, &[1, 64]);
+        u64s(&mut file, &[0, 1, 0x1 | 0x2 | 0x4 | 0x20 | 0x100]);
+        file.resize(104 + 80, 0);
+        file.extend_from_slice(&data);
+
+        let dir = TempDir::new("perf-data").unwrap();
+        let perf_data = dir.path().join("perf.data");
+        fs::write(&perf_data, &file).unwrap();
+
+        let mut samples = vec![];
+        read(&perf_data, Some(&mut Symbolizer::default()), |record| {
+            if let Record::Sample(sample) = record {
+                samples.push(sample);
+            }
+        })
+        .unwrap();
+
+        assert_eq!(samples.len(), 1);
+        let sample = &samples[0];
+        assert_eq!(
+            (
+                &*sample.comm,
+                sample.pid,
+                sample.tid,
+                sample.time,
+                sample.period
+            ),
+            ("worker 1", 10, 11, 1.5, 1000)
+        );
+        let names = sample
+            .stack
+            .iter()
+            .map(|frame| frame.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 2, "{:?}", names);
+        assert!(names[0].contains("perf_data4test6caller"), "{:?}", names);
+        assert!(names[1].contains("perf_data4test4leaf"), "{:?}", names);
+        assert_eq!(sample.stack[1].module.as_deref(), Some(&*path));
+    }
+}
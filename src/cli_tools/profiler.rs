@@ -1,10 +1,243 @@
+use super::dtrace::make_dtrace_attach_command;
+use super::dtrace::make_dtrace_command;
+use super::dtrace::DTRACE_OUTPUT_FILENAME;
+use super::perf::chown_to_user;
+use super::perf::make_perf_attach_command;
+use super::perf::make_perf_command;
+use super::perf::PERF_OUTPUT_FILENAME;
+use super::recording::Recording;
+use super::recording::SavedKind;
+use crate::cargo::BinFile;
+use crate::util::parse_duration;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
 #[cfg(unix)]
 use std::os::unix::process::ExitStatusExt;
+use std::path::Path;
 use std::process::Command;
 use std::process::ExitStatus;
+use std::str::FromStr;
+use std::time::Duration;
+use structopt::StructOpt;
+use tempdir::TempDir;
+
+// Options used to record the profile.
+#[derive(Debug, Clone, StructOpt)]
+pub struct RecordOptions {
+    /// Sampling frequency, in Hz.
+    #[structopt(long, value_name = "HZ")]
+    pub freq: Option<u32>,
+
+    /// Event to sample. e.g. `cycles`, `instructions`, `cache-misses`,
+    /// `branch-misses`, `task-clock`.
+    ///
+    /// Append `:u` to count only user space, like `cycles:u`.
+    /// Supported only by perf.
+    #[structopt(long)]
+    pub event: Option<String>,
+
+    /// Method used to unwind stacks. One of `dwarf`, `fp` or `lbr`.
+    ///
+    /// `fp` is much cheaper than `dwarf`, but requires binaries built with
+    /// frame pointers. Supported only by perf.
+    #[structopt(long, value_name = "MODE", default_value = "dwarf")]
+    pub call_graph: CallGraph,
+
+    /// Size of the stack dumped for each sample when `--call-graph dwarf` is
+    /// used, in bytes.
+    #[structopt(long, value_name = "BYTES")]
+    pub stack_size: Option<u32>,
+
+    /// Record context switches. Supported only by perf.
+    #[structopt(skip)]
+    pub switch_events: bool,
+
+    /// Record stacks of threads switched out by the scheduler, along with
+    /// `task-clock` if `--event` is not specified. Supported only by perf.
+    #[structopt(skip)]
+    pub sched_switch: bool,
+}
+
+impl RecordOptions {
+    /// Returns an error if options not supported by dtrace are used.
+    pub fn check_dtrace(&self) -> Result<(), Error> {
+        if self.event.is_some() {
+            bail!("`--event` is supported only by perf")
+        }
+        if self.call_graph != CallGraph::Dwarf || self.stack_size.is_some() {
+            bail!("`--call-graph` and `--stack-size` are supported only by perf")
+        }
+        if self.switch_events {
+            bail!("context switches can be recorded only by perf")
+        }
+        if self.sched_switch {
+            bail!("off-cpu time can be recorded only by perf")
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value of `--call-graph` for `perf record`.
+    pub fn perf_call_graph(&self) -> Result<String, Error> {
+        match (self.call_graph, self.stack_size) {
+            (CallGraph::Dwarf, Some(size)) => Ok(format!("dwarf,{}", size)),
+            (_, Some(..)) => bail!("`--stack-size` can be used only with `--call-graph dwarf`"),
+            (CallGraph::Dwarf, None) => Ok("dwarf".into()),
+            (CallGraph::Fp, None) => Ok("fp".into()),
+            (CallGraph::Lbr, None) => Ok("lbr".into()),
+        }
+    }
+}
+
+/// Method used to unwind stacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallGraph {
+    Dwarf,
+    /// Frame pointers.
+    Fp,
+    /// Last branch records of intel cpus.
+    Lbr,
+}
+
+impl FromStr for CallGraph {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dwarf" => Ok(CallGraph::Dwarf),
+            "fp" => Ok(CallGraph::Fp),
+            "lbr" => Ok(CallGraph::Lbr),
+            _ => Err(format!("unknown call graph mode: {}", s)),
+        }
+    }
+}
+
+// Options used to profile a running process.
+#[derive(Debug, Clone, StructOpt)]
+pub struct AttachOptions {
+    /// Attach to a running process instead of compiling and running the
+    /// target.
+    #[structopt(long)]
+    pub pid: Option<u32>,
+
+    /// Stop recording after this duration. e.g. `30s`, `500ms`, `2m`
+    ///
+    /// Without this, recording continues until ctrl+c is pressed or the
+    /// process exits.
+    #[structopt(long, parse(try_from_str = parse_duration), requires = "pid")]
+    pub duration: Option<Duration>,
+}
+
+impl AttachOptions {
+    pub fn target(&self) -> Option<ProfileTarget<'static>> {
+        self.pid.map(|pid| ProfileTarget::Pid {
+            pid,
+            duration: self.duration,
+        })
+    }
+}
+
+/// Process to profile.
+#[derive(Debug, Clone, Copy)]
+pub enum ProfileTarget<'a> {
+    /// Runs a binary built by cargo.
+    Binary {
+        file: &'a BinFile,
+        args: &'a [String],
+    },
+    /// Attaches to a running process.
+    Pid {
+        pid: u32,
+        duration: Option<Duration>,
+    },
+}
+
+/// Records the profile of `target` using dtrace or perf.
+///
+/// If `save_raw` is specified, the recorded data is stored there instead of a
+/// temporary directory.
+pub fn profile(
+    root: bool,
+    target: ProfileTarget,
+    opts: &RecordOptions,
+    save_raw: Option<&Path>,
+) -> Result<Recording, Error> {
+    if let ProfileTarget::Binary { file, .. } = &target {
+        file.check_runnable()?;
+    }
+
+    let dir = TempDir::new("cargo-profile").context("failed to create temp dir")?;
+
+    let raw_data_path = match save_raw {
+        Some(path) => path.to_path_buf(),
+        None if cfg!(target_os = "macos") => dir.path().join(DTRACE_OUTPUT_FILENAME),
+        None => dir.path().join(PERF_OUTPUT_FILENAME),
+    };
+
+    let cmd = if cfg!(target_os = "macos") {
+        opts.check_dtrace()?;
+
+        match target {
+            ProfileTarget::Binary { file, args } => {
+                make_dtrace_command(root, file, &raw_data_path, opts.freq, None, args)?
+            }
+            ProfileTarget::Pid { pid, duration } => {
+                make_dtrace_attach_command(root, pid, &raw_data_path, opts.freq, duration)?
+            }
+        }
+    } else if cfg!(target_os = "linux") {
+        match target {
+            ProfileTarget::Binary { file, args } => {
+                make_perf_command(root, file, &raw_data_path, opts, args)?
+            }
+            ProfileTarget::Pid { pid, duration } => {
+                make_perf_attach_command(root, pid, &raw_data_path, opts, duration)?
+            }
+        }
+    } else {
+        bail!("cargo profile currently supports only `linux` and `macos`")
+    };
+
+    match target {
+        ProfileTarget::Binary { file, .. } => {
+            eprintln!(
+                "Profiling {} ({})",
+                file.path.display(),
+                file.profile_summary()
+            );
+            if !file.runner.is_empty() {
+                eprintln!("Running it with `{}`", file.runner.join(" "));
+            }
+        }
+        ProfileTarget::Pid { pid, .. } => eprintln!("Profiling process {}", pid),
+    }
+
+    run_profiler(cmd).context("failed to profile program")?;
+
+    let kind = if cfg!(target_os = "macos") {
+        SavedKind::DtraceStacks
+    } else {
+        SavedKind::PerfData
+    };
+
+    if root && kind == SavedKind::PerfData {
+        chown_to_user(&raw_data_path).context("failed to change the owner of perf.data")?;
+    }
+
+    let binaries = match target {
+        ProfileTarget::Binary { file, .. } => vec![file.clone()],
+        ProfileTarget::Pid { .. } => vec![],
+    };
+
+    Ok(Recording::new(
+        root,
+        kind,
+        raw_data_path,
+        binaries,
+        Some(dir),
+    ))
+}
 
 /// Invokes profiler with proper signal hooks.
 ///
@@ -49,9 +282,7 @@ pub fn run_profiler(mut cmd: Command) -> Result<(), Error> {
 fn terminated_by_error(status: ExitStatus) -> bool {
     status
         .signal() // the default needs to be true because that's the neutral element for `&&`
-        .map_or(true, |code| {
-            code != signal_hook::SIGINT && code != signal_hook::SIGTERM
-        })
+        .is_none_or(|code| code != signal_hook::SIGINT && code != signal_hook::SIGTERM)
         && !status.success()
 }
 
@@ -0,0 +1,232 @@
+//! Raw data recorded by profilers.
+
+use crate::cargo::BinFile;
+use crate::stacks::offcpu::has_sched_switch;
+use crate::stacks::offcpu::to_collapsed_by_time;
+use crate::stacks::offcpu::Clock;
+use crate::stacks::split_threads;
+use crate::stacks::ContextSwitch;
+use crate::stacks::Sample;
+use crate::stacks::ThreadStacks;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Error;
+use std::fs;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::path::PathBuf;
+use tempdir::TempDir;
+
+/// Magic bytes at the start of a file recorded by `perf record`.
+const PERF_DATA_MAGIC: &[u8] = b"PERFILE2";
+
+/// Kind of a file recorded by a profiler or stored by previous runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SavedKind {
+    /// Collapsed stacks.
+    Folded,
+    /// Recorded by `perf record`.
+    PerfData,
+    /// Output of the dtrace script used by `cargo profile`.
+    DtraceStacks,
+}
+
+impl SavedKind {
+    pub fn detect(data: &[u8]) -> Self {
+        if data.starts_with(PERF_DATA_MAGIC) {
+            return SavedKind::PerfData;
+        }
+
+        if is_folded(&String::from_utf8_lossy(data)) {
+            SavedKind::Folded
+        } else {
+            SavedKind::DtraceStacks
+        }
+    }
+}
+
+/// Raw data recorded by a profiler, or stored by previous runs.
+#[derive(Debug)]
+pub(crate) struct Recording {
+    /// Use sudo to read the data.
+    root: bool,
+    kind: SavedKind,
+    path: PathBuf,
+    /// Profiled binaries, whose debuginfo is used to symbolize stacks.
+    binaries: Vec<BinFile>,
+    /// Removed on drop, if the data is recorded into a temporary directory.
+    _dir: Option<TempDir>,
+}
+
+impl Recording {
+    pub fn new(
+        root: bool,
+        kind: SavedKind,
+        path: PathBuf,
+        binaries: Vec<BinFile>,
+        dir: Option<TempDir>,
+    ) -> Self {
+        Recording {
+            root,
+            kind,
+            path,
+            binaries,
+            _dir: dir,
+        }
+    }
+
+    /// Opens `path`, which may contain collapsed stacks, `perf.data` or
+    /// dtrace stacks.
+    pub fn open(root: bool, path: &Path) -> Result<Self, Error> {
+        const HEAD_LEN: u64 = 64 * 1024;
+
+        let mut head = vec![];
+        File::open(path)
+            .and_then(|f| f.take(HEAD_LEN).read_to_end(&mut head))
+            .with_context(|| format!("failed to read {}", path.display()))?;
+
+        // Drop the partially read line.
+        if head.len() as u64 == HEAD_LEN {
+            if let Some(idx) = head.iter().rposition(|&b| b == b'\n') {
+                head.truncate(idx + 1);
+            }
+        }
+
+        Ok(Recording::new(
+            root,
+            SavedKind::detect(&head),
+            path.to_path_buf(),
+            vec![],
+            None,
+        ))
+    }
+
+    pub fn to_collapsed(&self) -> Result<Vec<u8>, Error> {
+        match self.kind {
+            SavedKind::Folded => fs::read(&self.path)
+                .with_context(|| format!("failed to read {}", self.path.display())),
+            SavedKind::PerfData => {
+                super::perf::to_collapsed(self.root, &self.path, &self.binaries, false)
+            }
+            SavedKind::DtraceStacks => super::dtrace::to_collapsed(&self.path, &self.binaries),
+        }
+    }
+
+    /// Returns collapsed stacks grouped by the thread name.
+    ///
+    /// Only perf records threads of stacks.
+    pub fn to_collapsed_per_thread(&self) -> Result<Vec<ThreadStacks>, Error> {
+        match self.kind {
+            SavedKind::PerfData => {
+                super::perf::to_collapsed(self.root, &self.path, &self.binaries, true)
+                    .map(|c| split_threads(&c))
+            }
+            SavedKind::Folded | SavedKind::DtraceStacks => bail!(
+                "threads of stacks are recorded only by perf, and {} does not contain them",
+                self.path.display()
+            ),
+        }
+    }
+
+    /// Returns collapsed stacks weighted by microseconds spent on or off
+    /// cpus. See [to_collapsed_by_time].
+    ///
+    /// Requires `perf.data` with scheduler switches, which are recorded if
+    /// [RecordOptions::sched_switch] is set.
+    ///
+    /// [RecordOptions::sched_switch]: super::profiler::RecordOptions::sched_switch
+    pub fn to_collapsed_by_time(&self, clock: Clock, threads: bool) -> Result<Vec<u8>, Error> {
+        let samples = match self.samples(false)? {
+            Some(samples) => samples,
+            None => bail!(
+                "off-cpu time is recorded only by perf, and {} does not contain it",
+                self.path.display()
+            ),
+        };
+        if clock != Clock::OnCpu && !has_sched_switch(&samples) {
+            bail!(
+                "{} does not contain scheduler switches. Record it with `--off-cpu` or \
+                 `--wall-clock`",
+                self.path.display()
+            );
+        }
+        let switches = self.context_switches()?.unwrap_or_default();
+
+        Ok(to_collapsed_by_time(&samples, &switches, clock, threads))
+    }
+
+    /// Returns samples with timestamps and threads, if the profiler recorded
+    /// them.
+    ///
+    /// Source files and lines of frames are read from debuginfo if it's
+    /// available. If `perf script` is used to read samples, it's done only if
+    /// `source_lines` is true, as it's slow.
+    pub fn samples(&self, source_lines: bool) -> Result<Option<Vec<Sample>>, Error> {
+        match self.kind {
+            SavedKind::PerfData => {
+                super::perf::read_samples(self.root, &self.path, &self.binaries, source_lines)
+                    .map(Some)
+            }
+            SavedKind::Folded | SavedKind::DtraceStacks => Ok(None),
+        }
+    }
+
+    /// Returns context switches, if the profiler recorded them.
+    pub fn context_switches(&self) -> Result<Option<Vec<ContextSwitch>>, Error> {
+        match self.kind {
+            SavedKind::PerfData => {
+                super::perf::read_context_switches(self.root, &self.path).map(Some)
+            }
+            SavedKind::Folded | SavedKind::DtraceStacks => Ok(None),
+        }
+    }
+}
+
+/// Loads collapsed stacks from `path`, which may contain collapsed stacks,
+/// `perf.data` or dtrace stacks.
+pub(crate) fn load_collapsed(root: bool, path: &Path) -> Result<Vec<u8>, Error> {
+    Recording::open(root, path)?.to_collapsed()
+}
+
+/// Returns true if every line looks like `a;b;c 10`.
+fn is_folded(data: &str) -> bool {
+    let mut lines = data
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .peekable();
+    if lines.peek().is_none() {
+        return false;
+    }
+
+    lines.take(100).all(|line| {
+        if line.starts_with(char::is_whitespace) {
+            return false;
+        }
+        match line.rsplit_once(' ') {
+            Some((stack, count)) => !stack.is_empty() && count.parse::<f64>().is_ok(),
+            None => false,
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn detect_kinds() {
+        assert_eq!(
+            SavedKind::detect(b"main;foo;bar 10\nmain;foo 3\n"),
+            SavedKind::Folded
+        );
+        assert_eq!(
+            SavedKind::detect(b"PERFILE2\x68\0\0\0"),
+            SavedKind::PerfData
+        );
+        assert_eq!(
+            SavedKind::detect(b"\n\n              libc`foo+0x12\n              a.out`main+0x10\n               3\n"),
+            SavedKind::DtraceStacks
+        );
+    }
+}
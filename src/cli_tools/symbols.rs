@@ -0,0 +1,217 @@
+//! Symbol tables of binaries and the kernel, used to name frames of
+//! `perf.data` without `perf script`.
+
+use crate::util::read_shared;
+use anyhow::Context;
+use anyhow::Error;
+use framehop::ExplicitModuleSectionInfo;
+use object::Object;
+use object::ObjectSection;
+use object::ObjectSegment;
+use object::ObjectSymbol;
+use object::SymbolKind;
+use std::fs;
+use std::ops::Deref;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Bytes of a loaded file, shared with the unwinder without copying.
+#[derive(Clone)]
+pub(crate) struct FileRange {
+    data: Arc<[u8]>,
+    range: Range<usize>,
+}
+
+impl Default for FileRange {
+    fn default() -> Self {
+        FileRange {
+            data: Arc::from(&[][..]),
+            range: 0..0,
+        }
+    }
+}
+
+impl Deref for FileRange {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data[self.range.clone()]
+    }
+}
+
+/// An ELF binary or shared library.
+pub(crate) struct Dso {
+    /// Functions as `(address, size, name)`, sorted by addresses.
+    symbols: Vec<(u64, u64, String)>,
+    /// Loadable segments as `(file offset, address)`, aligned like they are
+    /// mapped.
+    segments: Vec<(u64, u64)>,
+    sections: ExplicitModuleSectionInfo<FileRange>,
+}
+
+impl Dso {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data =
+            read_shared(path).with_context(|| format!("failed to read {}", path.display()))?;
+        let file = object::File::parse(&*data)
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+
+        let mut symbols = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|sym| sym.kind() == SymbolKind::Text && sym.address() != 0)
+            .filter_map(|sym| Some((sym.address(), sym.size(), sym.name().ok()?.to_string())))
+            .collect::<Vec<_>>();
+        // Keep the first of aliases, which is usually from `.symtab`.
+        symbols.sort_by_key(|&(addr, ..)| addr);
+        symbols.dedup_by_key(|&mut (addr, ..)| addr);
+
+        let segments = file
+            .segments()
+            .map(|seg| {
+                let mask = !(seg.align().max(1) - 1);
+                (seg.file_range().0 & mask, seg.address() & mask)
+            })
+            .collect();
+
+        let section = |name: &str| {
+            let section = file.section_by_name(name)?;
+            let (offset, size) = section.file_range()?;
+            let svma = section.address()..section.address() + section.size();
+            let range = FileRange {
+                data: data.clone(),
+                range: offset as usize..(offset + size) as usize,
+            };
+            Some((svma, range))
+        };
+        let (text_svma, text) = section(".text").unzip();
+        let (eh_frame_svma, eh_frame) = section(".eh_frame").unzip();
+        let (eh_frame_hdr_svma, eh_frame_hdr) = section(".eh_frame_hdr").unzip();
+        let sections = ExplicitModuleSectionInfo {
+            base_svma: 0,
+            text_svma,
+            text,
+            got_svma: file
+                .section_by_name(".got")
+                .map(|s| s.address()..s.address() + s.size()),
+            eh_frame_svma,
+            eh_frame,
+            eh_frame_hdr_svma,
+            eh_frame_hdr,
+            debug_frame: section(".debug_frame").map(|(_, range)| range),
+            ..Default::default()
+        };
+
+        Ok(Dso {
+            symbols,
+            segments,
+            sections,
+        })
+    }
+
+    /// Returns the address of the binary, which is mapped at `start` from
+    /// `page_offset` of the file, when its addresses start from 0.
+    pub fn base_address(&self, start: u64, page_offset: u64) -> u64 {
+        let (offset, address) = self
+            .segments
+            .iter()
+            .rev()
+            .find(|&&(offset, _)| offset <= page_offset)
+            .or_else(|| self.segments.first())
+            .copied()
+            .unwrap_or((0, 0));
+
+        start
+            .wrapping_sub(page_offset)
+            .wrapping_add(offset)
+            .wrapping_sub(address)
+    }
+
+    /// Returns the function containing `address`, which is relative to the
+    /// base address.
+    pub fn symbolize(&self, address: u64) -> Option<&str> {
+        let idx = self.symbols.partition_point(|&(addr, ..)| addr <= address);
+        let (addr, size, name) = self.symbols.get(idx.checked_sub(1)?)?;
+        if *size != 0 && address >= addr + size {
+            return None;
+        }
+
+        Some(name)
+    }
+
+    /// Returns addresses and names of functions.
+    pub fn functions(&self) -> impl Iterator<Item = (u64, &str)> {
+        self.symbols.iter().map(|(addr, _, name)| (*addr, &**name))
+    }
+
+    /// Sections used to unwind stacks.
+    pub fn unwind_sections(&self) -> ExplicitModuleSectionInfo<FileRange> {
+        self.sections.clone()
+    }
+}
+
+/// Functions of the kernel, read from `/proc/kallsyms`.
+#[derive(Debug, Default)]
+pub(crate) struct KernelSymbols {
+    /// Sorted by addresses.
+    symbols: Vec<(u64, String)>,
+}
+
+impl KernelSymbols {
+    /// Returns an empty table if addresses are hidden, which is the case
+    /// unless `kernel.kptr_restrict` allows them.
+    pub fn load() -> Self {
+        let s = fs::read_to_string("/proc/kallsyms").unwrap_or_default();
+
+        KernelSymbols::parse(&s)
+    }
+
+    /// Parses lines like `ffffffff81000000 T _stext`.
+    fn parse(s: &str) -> Self {
+        let mut symbols = s
+            .lines()
+            .filter_map(|line| {
+                let mut tokens = line.split_whitespace();
+                let addr = u64::from_str_radix(tokens.next()?, 16).ok()?;
+                let kind = tokens.next()?;
+                let name = tokens.next()?;
+                if addr == 0 || !matches!(kind, "t" | "T" | "w" | "W") {
+                    return None;
+                }
+
+                Some((addr, name.to_string()))
+            })
+            .collect::<Vec<_>>();
+        symbols.sort_by_key(|&(addr, _)| addr);
+
+        KernelSymbols { symbols }
+    }
+
+    pub fn symbolize(&self, address: u64) -> Option<&str> {
+        let idx = self.symbols.partition_point(|&(addr, _)| addr <= address);
+        let (_, name) = self.symbols.get(idx.checked_sub(1)?)?;
+
+        Some(name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn kernel_symbols() {
+        let symbols = KernelSymbols::parse(
+            "ffffffff81000100 T schedule
+ffffffff81000000 t __schedule
+ffffffff81000200 D jiffies
+0000000000000000 T hidden
+",
+        );
+
+        assert_eq!(symbols.symbolize(0xffffffff81000010), Some("__schedule"));
+        assert_eq!(symbols.symbolize(0xffffffff81000300), Some("schedule"));
+        assert_eq!(symbols.symbolize(0x10), None);
+    }
+}
@@ -0,0 +1,524 @@
+//! Default options and presets, read from `[workspace.metadata.cargo-profile]`,
+//! `[package.metadata.cargo-profile]` and `.cargo-profile.toml`.
+//!
+//! Keys are long names of command-line options, and values are inserted into
+//! the command line unless the option is already specified there. So options
+//! are validated by the parser of the command line, and options not supported
+//! by a command are ignored. Keys which are not options of any command are
+//! errors.
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Error;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use structopt::clap::App;
+use structopt::clap::Arg;
+use structopt::clap::ArgMatches;
+use structopt::clap::ErrorKind;
+use toml::Table;
+use toml::Value;
+
+/// Name of the optional config file, in the root of the workspace or the
+/// package.
+const FILE_NAME: &str = ".cargo-profile.toml";
+
+/// Key of the tables in `package.metadata` and `workspace.metadata`.
+const METADATA_KEY: &str = "cargo-profile";
+
+/// Name of the argument with arguments passed to the target binary.
+const TARGET_ARGS: &str = "target-args";
+
+const PRESET: &str = "preset";
+
+/// Options of the defaults or a preset.
+#[derive(Debug, Default)]
+struct Options {
+    /// Values of command-line options, and where they are defined.
+    options: BTreeMap<String, (Value, String)>,
+    /// Arguments passed to the target binary.
+    args: Option<Vec<String>>,
+    /// Environment variables of cargo and the target binary.
+    env: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Default)]
+struct Config {
+    defaults: Options,
+    presets: BTreeMap<String, Options>,
+    /// Files and tables read, for error messages.
+    sources: Vec<String>,
+}
+
+impl Config {
+    /// Reads tables of the workspace and the package, then config files,
+    /// which override the former.
+    fn load(manifest_path: Option<&Path>, package: Option<&str>) -> Result<Self, Error> {
+        let mut config = Config::default();
+
+        let mut cmd = cargo_metadata::MetadataCommand::new();
+        if let Some(path) = manifest_path {
+            cmd.manifest_path(path);
+        }
+        // Commands like `--from` can be used outside of packages. Errors of
+        // manifests are reported while building the target.
+        let metadata = match cmd.no_deps().exec() {
+            Ok(metadata) => metadata,
+            Err(_) => {
+                config.read_file(&env::current_dir()?.join(FILE_NAME))?;
+                return Ok(config);
+            }
+        };
+
+        let package = match package {
+            Some(name) => metadata.packages.iter().find(|p| p.name == name),
+            None => {
+                let manifest = match manifest_path {
+                    Some(path) => Some(path.canonicalize()?),
+                    None => None,
+                };
+                let cwd = env::current_dir()?;
+                // The package containing the current directory, like cargo.
+                metadata
+                    .packages
+                    .iter()
+                    .filter(|p| match &manifest {
+                        Some(manifest) => p.manifest_path == *manifest,
+                        None => p
+                            .manifest_path
+                            .parent()
+                            .is_some_and(|dir| cwd.starts_with(dir)),
+                    })
+                    .max_by_key(|p| p.manifest_path.components().count())
+            }
+        };
+
+        if let Some(table) = metadata.workspace_metadata.get(METADATA_KEY) {
+            config.read_metadata(table, "[workspace.metadata.cargo-profile]".into())?;
+        }
+        if let Some(package) = package {
+            if let Some(table) = package.metadata.get(METADATA_KEY) {
+                config.read_metadata(
+                    table,
+                    format!("[package.metadata.cargo-profile] of {}", package.name),
+                )?;
+            }
+        }
+
+        config.read_file(metadata.workspace_root.join(FILE_NAME).as_std_path())?;
+        if let Some(dir) = package.and_then(|p| p.manifest_path.parent()) {
+            if dir != metadata.workspace_root {
+                config.read_file(dir.join(FILE_NAME).as_std_path())?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    fn read_metadata(&mut self, table: &serde_json::Value, source: String) -> Result<(), Error> {
+        let table = serde_json::from_value(table.clone())
+            .with_context(|| format!("failed to parse {}", source))?;
+        self.merge(table, source)
+    }
+
+    fn read_file(&mut self, path: &Path) -> Result<(), Error> {
+        if !path.is_file() {
+            return Ok(());
+        }
+
+        let table = fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .parse::<Table>()
+            .with_context(|| format!("failed to parse {}", path.display()))?;
+        self.merge(table, path.display().to_string())
+    }
+
+    /// Overrides options with `table`.
+    fn merge(&mut self, mut table: Table, source: String) -> Result<(), Error> {
+        if let Some(presets) = table.remove("presets") {
+            let presets = match presets {
+                Value::Table(presets) => presets,
+                _ => bail!("`presets` in {} should be a table", source),
+            };
+            for (name, preset) in presets {
+                let preset = match preset {
+                    Value::Table(preset) => preset,
+                    _ => bail!("preset `{}` in {} should be a table", name, source),
+                };
+                self.presets
+                    .entry(name)
+                    .or_default()
+                    .merge(preset, &source)?;
+            }
+        }
+
+        self.defaults.merge(table, &source)?;
+        self.sources.push(source);
+
+        Ok(())
+    }
+
+    fn preset(&self, name: &str) -> Result<&Options, Error> {
+        match self.presets.get(name) {
+            Some(preset) => Ok(preset),
+            None if self.sources.is_empty() => bail!(
+                "preset `{}` is not defined, as `[package.metadata.cargo-profile]`, \
+                 `[workspace.metadata.cargo-profile]` and `{}` are not found",
+                name,
+                FILE_NAME
+            ),
+            None => bail!(
+                "preset `{}` is not defined in {}",
+                name,
+                self.sources.join(", ")
+            ),
+        }
+    }
+}
+
+impl Options {
+    fn merge(&mut self, table: Table, source: &str) -> Result<(), Error> {
+        for (key, value) in table {
+            match (&*key, value) {
+                ("args", Value::Array(args)) => {
+                    let args = args
+                        .iter()
+                        .map(|arg| scalar(arg).with_context(|| invalid("args", source)))
+                        .collect::<Result<_, _>>()?;
+                    self.args = Some(args);
+                }
+                ("env", Value::Table(vars)) => {
+                    for (name, value) in vars {
+                        let value = scalar(&value).with_context(|| invalid("env", source))?;
+                        self.env.insert(name, value);
+                    }
+                }
+                ("args", _) => bail!("`args` in {} should be an array", source),
+                ("env", _) => bail!("`env` in {} should be a table", source),
+                (_, Value::Table(..)) | (_, Value::Datetime(..)) => {
+                    bail!("{}", invalid(&key, source))
+                }
+                (_, value) => {
+                    self.options.insert(key, (value, source.to_string()));
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(key: &str, source: &str) -> String {
+    format!("invalid value of `{}` in {}", key, source)
+}
+
+/// Returns strings, numbers and booleans as strings.
+fn scalar(value: &Value) -> Result<String, Error> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(v) => Ok(v.to_string()),
+        Value::Float(v) => Ok(v.to_string()),
+        Value::Boolean(v) => Ok(v.to_string()),
+        _ => bail!("expected a string or a number, but got {}", value),
+    }
+}
+
+/// Returns command-line arguments for an option. Arrays are used for options
+/// which can be used multiple times.
+fn option_args(key: &str, value: &Value) -> Result<Vec<OsString>, Error> {
+    match value {
+        Value::Boolean(true) => Ok(vec![format!("--{}", key).into()]),
+        Value::Boolean(false) => Ok(vec![]),
+        Value::Array(values) => values
+            .iter()
+            .map(|value| Ok(format!("--{}={}", key, scalar(value)?).into()))
+            .collect(),
+        // Values starting with `-` are not parsed as options.
+        _ => Ok(vec![format!("--{}={}", key, scalar(value)?).into()]),
+    }
+}
+
+/// Returns `--preset`, which can be used with every subcommand.
+pub(crate) fn preset_arg() -> Arg<'static, 'static> {
+    Arg::with_name(PRESET)
+        .long(PRESET)
+        .value_name("NAME")
+        .global(true)
+        .help(
+            "Use options of `[presets.<NAME>]` in `[package.metadata.cargo-profile]`, \
+             `[workspace.metadata.cargo-profile]` or `.cargo-profile.toml`. Options of the \
+             command line override the preset",
+        )
+}
+
+/// Returns long names of options of `app` and its subcommands.
+fn long_options(app: &App, names: &mut BTreeSet<String>) {
+    // clap 2 has no other way to list arguments.
+    let flags = app.p.flags.iter().map(|flag| flag.s.long);
+    let opts = app.p.opts.iter().map(|opt| opt.s.long);
+    names.extend(flags.chain(opts).flatten().map(String::from));
+
+    for sub in &app.p.subcommands {
+        long_options(sub, names);
+    }
+}
+
+/// Returns names of subcommands and the matches of the innermost one.
+fn leaf<'a, 'b>(matches: &'a ArgMatches<'b>) -> (Vec<&'a str>, &'a ArgMatches<'b>) {
+    let mut names = vec![];
+    let mut matches = matches;
+    while let (name, Some(sub)) = matches.subcommand() {
+        names.push(name);
+        matches = sub;
+    }
+    (names, matches)
+}
+
+/// Inserts options of config files into `args`, which are the command line
+/// without `cargo`, and sets environment variables of the config.
+///
+/// Options of the preset selected by `--preset` override the defaults, and
+/// options in `args` override both of them.
+pub(crate) fn apply(
+    args: Vec<OsString>,
+    app: impl Fn() -> App<'static, 'static>,
+) -> Result<Vec<OsString>, Error> {
+    let matches = app().get_matches_from(&args);
+    let (_, leaf_matches) = leaf(&matches);
+
+    let manifest_path = leaf_matches.value_of_os("manifest-path").map(PathBuf::from);
+    let package = match leaf_matches.values_of("package") {
+        Some(mut packages) if packages.len() == 1 => packages.next(),
+        _ => None,
+    };
+    let config = Config::load(manifest_path.as_deref(), package)?;
+    let layers = layers(&config, &matches)?;
+
+    let mut vars = BTreeMap::new();
+    for options in layers.iter().rev() {
+        vars.extend(&options.env);
+    }
+    for (name, value) in vars {
+        // Variables of the environment override the config.
+        if env::var_os(name).is_none() {
+            env::set_var(name, value);
+        }
+    }
+
+    insert(args, &matches, app, &layers)
+}
+
+/// Returns the preset selected by `--preset`, if any, and the defaults.
+fn layers<'a>(config: &'a Config, matches: &ArgMatches) -> Result<Vec<&'a Options>, Error> {
+    let mut layers = vec![];
+    let preset = leaf(matches)
+        .1
+        .value_of(PRESET)
+        .or_else(|| matches.value_of(PRESET));
+    if let Some(name) = preset {
+        layers.push(config.preset(name)?);
+    }
+    layers.push(&config.defaults);
+
+    Ok(layers)
+}
+
+/// Inserts options of `layers` missing in `args`. Former layers override
+/// latter ones.
+fn insert(
+    mut args: Vec<OsString>,
+    matches: &ArgMatches,
+    app: impl Fn() -> App<'static, 'static>,
+    layers: &[&Options],
+) -> Result<Vec<OsString>, Error> {
+    let mut known = BTreeSet::new();
+    long_options(&app(), &mut known);
+    for options in layers {
+        for (key, (_, source)) in &options.options {
+            if !known.contains(key) {
+                bail!("`{}` in {} is not an option of cargo-profile", key, source)
+            }
+        }
+    }
+
+    let (names, leaf_matches) = leaf(matches);
+
+    // Options are inserted after the name of the innermost subcommand, as
+    // arguments after the first positional one are passed to the target.
+    let mut at = 0;
+    for name in names {
+        at += match args[at + 1..].iter().position(|arg| arg == name) {
+            Some(idx) => idx + 1,
+            None => return Ok(args),
+        };
+    }
+
+    let mut inserted = vec![];
+    for options in layers {
+        for (key, (value, source)) in &options.options {
+            if inserted.contains(key) || leaf_matches.occurrences_of(key) > 0 {
+                continue;
+            }
+
+            let option = option_args(key, value)?;
+            let len = option.len();
+            let mut candidate = args.clone();
+            candidate.splice(at + 1..at + 1, option);
+            match app().get_matches_from_safe(&candidate) {
+                Ok(..) => {
+                    args = candidate;
+                    at += len;
+                    inserted.push(key.clone());
+                }
+                // Options of other commands, and options conflicting with
+                // ones of the command line or the preset.
+                Err(err)
+                    if matches!(
+                        err.kind,
+                        ErrorKind::UnknownArgument
+                            | ErrorKind::ArgumentConflict
+                            | ErrorKind::MissingRequiredArgument
+                            | ErrorKind::UnexpectedMultipleUsage
+                    ) => {}
+                Err(err) => return Err(err).with_context(|| invalid(key, source)),
+            }
+        }
+    }
+
+    if !leaf_matches.is_present(TARGET_ARGS) {
+        if let Some(target_args) = layers.iter().find_map(|options| options.args.as_ref()) {
+            let mut candidate = args.clone();
+            if candidate.last().is_none_or(|arg| arg != "--") {
+                candidate.push("--".into());
+            }
+            candidate.extend(target_args.iter().map(OsString::from));
+
+            // Commands without the target have no arguments for it.
+            if let Ok(matches) = app().get_matches_from_safe(&candidate) {
+                if leaf(&matches).1.is_present(TARGET_ARGS) {
+                    args = candidate;
+                }
+            }
+        }
+    }
+
+    Ok(args)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> Config {
+        let table = r#"
+            release = true
+            freq = 1999
+            min-width = 0.5
+            features = ["a", "b"]
+            args = ["--input", "data.json"]
+
+            [presets.parser]
+            profiling = true
+            bench = "parser"
+        "#;
+
+        let mut config = Config::default();
+        config
+            .merge(table.parse().unwrap(), "Cargo.toml".into())
+            .unwrap();
+        config
+    }
+
+    fn apply_config(cmdline: &str) -> Vec<String> {
+        let args = cmdline
+            .split_whitespace()
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        let config = config();
+        let matches = crate::app().get_matches_from(&args);
+        let layers = layers(&config, &matches).unwrap();
+
+        insert(args, &matches, crate::app, &layers)
+            .unwrap()
+            .into_iter()
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        assert_eq!(
+            apply_config("cargo-profile flamegraph --freq 99 -- x"),
+            "cargo-profile flamegraph --features=a --features=b --min-width=0.5 --release --freq \
+             99 -- x"
+                .split_whitespace()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn preset_overrides_defaults() {
+        assert_eq!(
+            apply_config("cargo-profile flamegraph --preset parser --release"),
+            "cargo-profile flamegraph --bench=parser --features=a --features=b --freq=1999 \
+             --min-width=0.5 --preset parser --release -- --input data.json"
+                .split_whitespace()
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn unknown_keys_are_errors() {
+        let mut config = Config::default();
+        config
+            .merge("freqq = 99".parse().unwrap(), "Cargo.toml".into())
+            .unwrap();
+        let args = vec![OsString::from("cargo-profile"), "flamegraph".into()];
+        let matches = crate::app().get_matches_from(&args);
+        let layers = layers(&config, &matches).unwrap();
+
+        let err = insert(args, &matches, crate::app, &layers).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`freqq` in Cargo.toml is not an option of cargo-profile"
+        );
+    }
+
+    #[test]
+    fn unstable_flags_can_be_configured() {
+        let mut config = Config::default();
+        config
+            .merge(
+                r#"unstable-flags = ["build-std"]"#.parse().unwrap(),
+                "Cargo.toml".into(),
+            )
+            .unwrap();
+        let args = vec![OsString::from("cargo-profile"), "flamegraph".into()];
+        let matches = crate::app().get_matches_from(&args);
+        let layers = layers(&config, &matches).unwrap();
+
+        assert_eq!(
+            insert(args, &matches, crate::app, &layers).unwrap(),
+            vec![
+                OsString::from("cargo-profile"),
+                "flamegraph".into(),
+                "--unstable-flags=build-std".into()
+            ]
+        );
+    }
+
+    #[test]
+    fn options_of_other_commands_are_ignored() {
+        assert_eq!(
+            apply_config("cargo-profile bin-path"),
+            "cargo-profile bin-path --features=a --features=b --release -- --input data.json"
+                .split_whitespace()
+                .collect::<Vec<_>>()
+        );
+    }
+}
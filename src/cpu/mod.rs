@@ -1,16 +1,19 @@
 use crate::cargo::compile;
 use crate::cargo::CargoTarget;
-use crate::cli_tools::dtrace::make_dtrace_command;
-use crate::cli_tools::profiler::run_profiler;
+use crate::cli_tools::profiler::profile;
+use crate::cli_tools::profiler::AttachOptions;
+use crate::cli_tools::profiler::ProfileTarget;
+use crate::cli_tools::profiler::RecordOptions;
+use crate::cli_tools::recording::Recording;
+use crate::stacks::for_each_stack;
+use crate::stacks::StackCounts;
+use crate::stacks::StackOptions;
+use crate::stacks::ThreadStacks;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
 use std::collections::HashMap;
-use std::collections::HashSet;
 use structopt::StructOpt;
-use tempdir::TempDir;
-
-mod merge;
 
 /// WIP: Profiles cpu usage.
 #[derive(Debug, Clone, StructOpt)]
@@ -23,6 +26,20 @@ pub enum CpuCommand {
         #[structopt(long)]
         root: bool,
 
+        #[structopt(flatten)]
+        attach: AttachOptions,
+
+        #[structopt(flatten)]
+        record: RecordOptions,
+
+        #[structopt(flatten)]
+        stacks: StackOptions,
+
+        /// Print a table for each thread name, so thread pools can be
+        /// compared. Requires perf.
+        #[structopt(long)]
+        per_thread: bool,
+
         /// Compile library
         #[structopt(flatten)]
         target: CargoTarget,
@@ -32,51 +49,44 @@ pub enum CpuCommand {
 impl CpuCommand {
     pub fn run(self) -> Result<(), Error> {
         match self {
-            CpuCommand::PerFn { root, target } => {
+            CpuCommand::PerFn {
+                root,
+                attach,
+                record,
+                stacks,
+                per_thread,
+                target,
+            } => {
+                let report = |recording: Recording| {
+                    if per_thread {
+                        print_per_thread(&recording.to_collapsed_per_thread()?, &stacks)
+                    } else {
+                        let mut collapsed = recording.to_collapsed()?;
+                        if cfg!(target_os = "linux") {
+                            collapsed = strip_threads(&collapsed);
+                        }
+                        print_per_fn(&stacks.apply(&collapsed))
+                    }
+                };
+
+                if let Some(profilee) = attach.target() {
+                    return report(profile(root, profilee, &record, None)?);
+                }
+
                 let binaries = compile(&target).context("failed to compile")?;
 
                 for binary in &binaries {
-                    let dir =
-                        TempDir::new("cargo-profile-cpu").context("failed to create temp dir")?;
-
-                    let cmd = if cfg!(target_os = "macos") {
-                        make_dtrace_command(
-                            root,
-                            binary,
-                            &dir.path().join("program.stacks"),
-                            None,
-                            None,
-                            target.args(),
-                        )?
-                    } else {
-                        bail!("cargo profile cpu currently supports only `macos`")
-                    };
-                    run_profiler(cmd).context("failed to profile program")?;
-
-                    let collapsed: Vec<u8> = if cfg!(target_os = "macos") {
-                        crate::cli_tools::dtrace::to_collapsed(&dir.path().join("program.stacks"))?
-                    } else {
-                        unreachable!()
-                    };
-
-                    let collapsed = String::from_utf8_lossy(&collapsed);
-
-                    let (time, mut data) = process_collapsed(&collapsed)
-                        .context("failed to process collapsed stack data")?;
-                    data.sort_by_key(|info| info.total_used);
-
-                    println!(
-                        "{: <10}  | {: <10}  | {}",
-                        "Totql time", "Own time", "File name",
-                    );
-                    for info in data.iter().rev() {
-                        println!(
-                            "{: <10.1}% | {: <10.1}% | {}",
-                            info.total_used as f64 / time as f64 * 100f64,
-                            info.self_used as f64 / time as f64 * 100f64,
-                            info.name,
-                        );
-                    }
+                    let recording = profile(
+                        root,
+                        ProfileTarget::Binary {
+                            file: binary,
+                            args: target.args(),
+                        },
+                        &record,
+                        None,
+                    )?;
+
+                    report(recording)?;
                 }
 
                 Ok(())
@@ -85,64 +95,151 @@ impl CpuCommand {
     }
 }
 
-struct FnTimingInfo {
-    name: String,
-    total_used: usize,
-    /// The percentage of time used by function code itself.
-    self_used: usize,
-}
-
-fn process_collapsed(data: &str) -> Result<(usize, Vec<FnTimingInfo>), Error> {
-    let mut lines: Vec<&str> = data.lines().into_iter().collect();
-    lines.reverse();
-    let (frames, time, ignored) =
-        merge::frames(lines, true).context("failed to merge collapsed stack frame")?;
-
+fn print_per_fn(collapsed: &[u8]) -> Result<(), Error> {
+    let (time, mut data) = process_collapsed(collapsed);
     if time == 0 {
         bail!("No stack counts found")
     }
-
-    if ignored > 0 {
-        eprintln!("ignored {} lines with invalid format", ignored)
+    data.sort_by(|a, b| {
+        a.total_used
+            .cmp(&b.total_used)
+            .then_with(|| b.name.cmp(&a.name))
+    });
+
+    println!("{: <10}  | {: <10}  | File name", "Totql time", "Own time");
+    for info in data.iter().rev() {
+        println!(
+            "{: <10.1}% | {: <10.1}% | {}",
+            info.total_used as f64 / time as f64 * 100f64,
+            info.self_used as f64 / time as f64 * 100f64,
+            info.name,
+        );
     }
 
-    let mut total_time = HashMap::<_, usize>::new();
-    let mut itself_time = HashMap::<_, usize>::new();
-
-    // Check if time collapses
-    for frame in &frames {
-        let fn_dur = frame.end_time - frame.start_time;
-        *total_time.entry(frame.location.function).or_default() += fn_dur;
+    Ok(())
+}
 
-        let children = frames.iter().filter(|child| {
-            frame.location.depth + 1 == child.location.depth
-                && frame.start_time <= child.start_time
-                && child.end_time <= frame.end_time
-        });
+/// Removes the root frame of stacks collapsed from perf, which is the name of
+/// the thread. dtrace does not record it.
+fn strip_threads(collapsed: &[u8]) -> Vec<u8> {
+    let mut counts = StackCounts::default();
+    for_each_stack(collapsed, |stack, count| {
+        if let Some((_, stack)) = stack.split_once(';') {
+            counts.add(stack, count);
+        }
+    });
+    counts.into_collapsed()
+}
 
-        let mut itself_dur = fn_dur;
+/// Prints a table for each thread name, in order of the number of samples.
+fn print_per_thread(threads: &[ThreadStacks], stacks: &StackOptions) -> Result<(), Error> {
+    let total = threads.iter().map(|t| t.samples).sum::<usize>();
+    if total == 0 {
+        bail!("No stack counts found")
+    }
 
-        for child in children {
-            let fn_dur = child.end_time - child.start_time;
-            itself_dur -= fn_dur;
+    for (i, thread) in threads.iter().enumerate() {
+        let collapsed = stacks.apply(&thread.collapsed);
+        if collapsed.is_empty() {
+            continue;
         }
 
-        *itself_time.entry(frame.location.function).or_default() += itself_dur;
+        if i != 0 {
+            println!();
+        }
+        println!(
+            "Thread {} ({} thread{}, {:.1}% of samples)",
+            thread.name,
+            thread.tids.len(),
+            if thread.tids.len() == 1 { "" } else { "s" },
+            thread.samples as f64 / total as f64 * 100f64
+        );
+        print_per_fn(&collapsed)
+            .with_context(|| format!("failed to print functions of thread {}", thread.name))?;
     }
 
-    let mut result = vec![];
-    let mut done = HashSet::new();
+    Ok(())
+}
 
-    for frame in &frames {
-        if !done.insert(&frame.location.function) {
-            continue;
+struct FnTimingInfo {
+    name: String,
+    total_used: usize,
+    /// The percentage of time used by function code itself.
+    self_used: usize,
+}
+
+/// Sums sample counts of stacks containing each function, and of stacks
+/// ending with it. Stacks are streamed, so memory usage depends on the
+/// number of functions.
+fn process_collapsed(collapsed: &[u8]) -> (usize, Vec<FnTimingInfo>) {
+    let mut time = 0;
+    let mut functions = HashMap::<String, FnTimingInfo>::new();
+
+    for_each_stack(collapsed, |stack, count| {
+        time += count;
+
+        let mut frames = stack.split(';').peekable();
+        while let Some(frame) = frames.next() {
+            if !functions.contains_key(frame) {
+                functions.insert(
+                    frame.to_string(),
+                    FnTimingInfo {
+                        name: frame.to_string(),
+                        total_used: 0,
+                        self_used: 0,
+                    },
+                );
+            }
+            let info = functions.get_mut(frame).unwrap();
+            info.total_used += count;
+            if frames.peek().is_none() {
+                info.self_used += count;
+            }
         }
-        result.push(FnTimingInfo {
-            name: frame.location.function.to_string(),
-            total_used: *total_time.entry(frame.location.function).or_default(),
-            self_used: *itself_time.entry(frame.location.function).or_default(),
-        });
+    });
+
+    (time, functions.into_values().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn functions_are_summed() {
+        let (time, mut data) = process_collapsed(b"main;parse;lex 3\nmain;parse 1\nmain;check 2\n");
+        data.sort_by(|a, b| a.name.cmp(&b.name));
+
+        assert_eq!(time, 6);
+        assert_eq!(
+            data.iter()
+                .map(|info| (&*info.name, info.total_used, info.self_used))
+                .collect::<Vec<_>>(),
+            vec![
+                ("check", 2, 2),
+                ("lex", 3, 3),
+                ("main", 6, 0),
+                ("parse", 4, 1)
+            ]
+        );
     }
 
-    Ok((time, result))
+    #[test]
+    fn threads_of_perf_are_not_functions() {
+        let (time, data) = process_collapsed(&strip_threads(
+            b"main;main;parse 3
+worker;run 1
+",
+        ));
+
+        assert_eq!(time, 4);
+        assert!(data.iter().all(|info| info.name != "worker"));
+        assert_eq!(
+            data.iter()
+                .find(|info| info.name == "main")
+                .unwrap()
+                .total_used,
+            3
+        );
+    }
 }
@@ -0,0 +1,284 @@
+//! https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+
+use super::group_by_thread;
+use super::sampling_interval;
+use super::Profile;
+use crate::stacks::ContextSwitch;
+use crate::stacks::Sample;
+use anyhow::bail;
+use anyhow::Error;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::io::Write;
+
+/// Writes trace events with a track per thread.
+///
+/// A thread has `running` slices while it's on a cpu, and `off-cpu` or
+/// `preempted` slices between them. Sampled stacks are nested in `running`
+/// slices.
+pub(super) fn write(profile: &Profile, w: &mut dyn Write) -> Result<(), Error> {
+    let samples = match &profile.samples {
+        Some(samples) if !samples.is_empty() => samples,
+        _ => bail!("trace events require samples with timestamps, which are recorded only by perf"),
+    };
+
+    let threads = group_by_thread(samples);
+    let interval = sampling_interval(&threads);
+
+    let mut switches = BTreeMap::<_, Vec<&ContextSwitch>>::new();
+    for switch in &profile.context_switches {
+        switches
+            .entry((switch.pid, switch.tid))
+            .or_default()
+            .push(switch);
+    }
+
+    let start = samples
+        .iter()
+        .map(|s| s.time)
+        .chain(profile.context_switches.iter().map(|s| s.time))
+        .fold(f64::INFINITY, f64::min);
+    let micros = |time: f64| (time - start) * 1e6;
+
+    let mut events = vec![];
+
+    let mut names = BTreeMap::new();
+    for samples in threads.values() {
+        let last = samples[samples.len() - 1];
+        names.insert((last.pid, last.tid), &*last.comm);
+    }
+    for (&(pid, tid), name) in &names {
+        if pid == tid || !names.contains_key(&(pid, pid)) {
+            events.push(json!({
+                "name": "process_name",
+                "ph": "M",
+                "pid": pid,
+                "args": { "name": name },
+            }));
+        }
+        events.push(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": pid,
+            "tid": tid,
+            "args": { "name": name },
+        }));
+    }
+
+    let keys = threads
+        .keys()
+        .chain(switches.keys())
+        .copied()
+        .collect::<BTreeSet<_>>();
+    for (pid, tid) in keys {
+        let samples = threads.get(&(pid, tid)).map_or(&[][..], |s| &s[..]);
+        let switches = switches.get(&(pid, tid)).map_or(&[][..], |s| &s[..]);
+
+        for slice in timeline(samples, switches, interval) {
+            events.push(json!({
+                "name": slice.name,
+                "cat": slice.cat,
+                "ph": "X",
+                "pid": pid,
+                "tid": tid,
+                "ts": micros(slice.start),
+                "dur": (slice.end - slice.start) * 1e6,
+            }));
+        }
+    }
+
+    serde_json::to_writer(
+        w,
+        &json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+        }),
+    )?;
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Slice<'a> {
+    name: &'a str,
+    cat: &'static str,
+    start: f64,
+    end: f64,
+    /// `0` for scheduling slices, and `n + 1` for the `n`th frame.
+    depth: usize,
+}
+
+enum Event<'a> {
+    Sample(&'a Sample),
+    Switch(&'a ContextSwitch),
+}
+
+impl Event<'_> {
+    /// Switching in comes first and switching out comes last, if timestamps
+    /// are same.
+    fn key(&self) -> (f64, u8) {
+        match self {
+            Event::Switch(s) if !s.out => (s.time, 0),
+            Event::Sample(s) => (s.time, 1),
+            Event::Switch(s) => (s.time, 2),
+        }
+    }
+}
+
+/// Creates slices of a thread.
+///
+/// A sampled stack lasts until the next sample, the thread is switched out,
+/// or for the sampling interval if the thread is not sampled for a while.
+fn timeline<'a>(
+    samples: &[&'a Sample],
+    switches: &[&'a ContextSwitch],
+    interval: f64,
+) -> Vec<Slice<'a>> {
+    let mut events = samples
+        .iter()
+        .map(|&s| Event::Sample(s))
+        .chain(switches.iter().map(|&s| Event::Switch(s)))
+        .collect::<Vec<_>>();
+    events.sort_by(|a, b| {
+        let (a, b) = (a.key(), b.key());
+        a.0.total_cmp(&b.0).then(a.1.cmp(&b.1))
+    });
+
+    let mut slices = vec![];
+    // Frames and the time they are opened at.
+    let mut open = Vec::<(&str, f64)>::new();
+    let mut running = None;
+    let mut off_cpu = None;
+    let mut last_sample = None;
+
+    let close = |slices: &mut Vec<Slice<'a>>, open: &mut Vec<(&'a str, f64)>, len, at: f64| {
+        while open.len() > len {
+            let (name, start) = open.pop().unwrap();
+            slices.push(Slice {
+                name,
+                cat: "stack",
+                start,
+                end: at.max(start),
+                depth: open.len() + 1,
+            });
+        }
+    };
+
+    for event in &events {
+        match *event {
+            Event::Switch(switch) if !switch.out => {
+                if let Some((since, preempted)) = off_cpu.take() {
+                    slices.push(Slice {
+                        name: if preempted { "preempted" } else { "off-cpu" },
+                        cat: "sched",
+                        start: since,
+                        end: switch.time,
+                        depth: 0,
+                    });
+                }
+                running.get_or_insert(switch.time);
+            }
+            Event::Switch(switch) => {
+                close(&mut slices, &mut open, 0, switch.time);
+                if let Some(since) = running.take() {
+                    slices.push(Slice {
+                        name: "running",
+                        cat: "sched",
+                        start: since,
+                        end: switch.time,
+                        depth: 0,
+                    });
+                }
+                off_cpu = Some((switch.time, switch.preempted));
+            }
+            Event::Sample(sample) => {
+                // Context switches may not be recorded.
+                if running.is_none() {
+                    off_cpu = None;
+                    running = Some(sample.time);
+                }
+
+                if let Some(last) = last_sample {
+                    if sample.time > last + 2.0 * interval {
+                        close(&mut slices, &mut open, 0, last + interval);
+                    }
+                }
+                last_sample = Some(sample.time);
+
+                let common = open
+                    .iter()
+                    .zip(&sample.stack)
+                    .take_while(|((name, _), frame)| *name == frame.name)
+                    .count();
+                close(&mut slices, &mut open, common, sample.time);
+
+                for frame in &sample.stack[common..] {
+                    open.push((&frame.name, sample.time));
+                }
+            }
+        }
+    }
+
+    let end = match (last_sample, events.last()) {
+        (Some(last), Some(Event::Sample(..))) => last + interval,
+        (_, Some(event)) => event.key().0,
+        (_, None) => return slices,
+    };
+    close(&mut slices, &mut open, 0, end);
+    if let Some(since) = running.filter(|&since| since < end) {
+        slices.push(Slice {
+            name: "running",
+            cat: "sched",
+            start: since,
+            end,
+            depth: 0,
+        });
+    }
+
+    // Parents come first, so viewers can nest slices with same timestamps.
+    slices.sort_by(|a, b| a.start.total_cmp(&b.start).then(a.depth.cmp(&b.depth)));
+
+    slices
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slices_are_nested() {
+        let sample = |time: f64, stack: &[&str]| Sample::new(1, time, stack);
+        let switch = |time: f64, out: bool| ContextSwitch {
+            pid: 1,
+            tid: 1,
+            time,
+            out,
+            preempted: false,
+        };
+
+        let samples = [sample(1.0, &["main", "a"]), sample(2.0, &["main", "b"])];
+        let switches = [switch(0.5, false), switch(2.5, true), switch(4.0, false)];
+
+        let slices = timeline(
+            &samples.iter().collect::<Vec<_>>(),
+            &switches.iter().collect::<Vec<_>>(),
+            1.0,
+        );
+        let slices = slices
+            .iter()
+            .map(|s| format!("{}:{}-{}", s.name, s.start, s.end))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            slices,
+            vec![
+                "running:0.5-2.5",
+                "main:1-2.5",
+                "a:1-2",
+                "b:2-2.5",
+                "off-cpu:2.5-4",
+            ]
+        );
+    }
+}
@@ -0,0 +1,129 @@
+//! https://github.com/firefox-devtools/profiler/blob/main/docs-developer/CHANGELOG-formats.md
+
+use super::group_by_thread;
+use super::sampling_interval;
+use super::Profile;
+use anyhow::bail;
+use anyhow::Error;
+use fxprof_processed_profile::CategoryHandle;
+use fxprof_processed_profile::CpuDelta;
+use fxprof_processed_profile::Frame;
+use fxprof_processed_profile::FrameFlags;
+use fxprof_processed_profile::FrameInfo;
+use fxprof_processed_profile::Profile as FxProfile;
+use fxprof_processed_profile::ReferenceTimestamp;
+use fxprof_processed_profile::SamplingInterval;
+use fxprof_processed_profile::Timestamp;
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::SystemTime;
+
+/// Writes a processed profile with a track per thread.
+///
+/// Timestamps are relative to the first sample, because perf records
+/// timestamps of a monotonic clock.
+pub(super) fn write(profile: &Profile, w: &mut dyn Write) -> Result<(), Error> {
+    let samples = match &profile.samples {
+        Some(samples) if !samples.is_empty() => samples,
+        _ => bail!(
+            "the firefox profiler requires samples with timestamps, which are recorded only by \
+             perf"
+        ),
+    };
+
+    let threads = group_by_thread(samples);
+    let interval = sampling_interval(&threads);
+    let start = samples.iter().map(|s| s.time).fold(f64::INFINITY, f64::min);
+    let timestamp =
+        |time: f64| Timestamp::from_nanos_since_reference(((time - start) * 1e9) as u64);
+
+    let mut fx = FxProfile::new(
+        &profile.name,
+        ReferenceTimestamp::from_system_time(SystemTime::now()),
+        SamplingInterval::from_nanos((interval * 1e9) as u64),
+    );
+    fx.set_symbolicated(true);
+
+    let mut processes = HashMap::new();
+    for (&(pid, tid), samples) in &threads {
+        let first = samples[0];
+        let last = samples[samples.len() - 1];
+
+        let process = *processes
+            .entry(pid)
+            .or_insert_with(|| fx.add_process(&first.comm, pid, timestamp(first.time)));
+        if tid == pid {
+            fx.set_process_name(process, &last.comm);
+        }
+
+        let thread = fx.add_thread(process, tid, timestamp(first.time), tid == pid);
+        fx.set_thread_name(thread, &last.comm);
+        fx.set_thread_end_time(thread, timestamp(last.time + interval));
+
+        for sample in samples {
+            let frames = sample
+                .stack
+                .iter()
+                .map(|frame| FrameInfo {
+                    frame: Frame::Label(fx.intern_string(&frame.name)),
+                    category_pair: CategoryHandle::OTHER.into(),
+                    flags: FrameFlags::empty(),
+                })
+                .collect::<Vec<_>>();
+            let stack = fx.intern_stack_frames(thread, frames.into_iter());
+
+            // Periods of these events are cpu time in nanoseconds.
+            let cpu_delta = match sample.event.as_deref().and_then(|e| e.split(':').next()) {
+                Some("cpu-clock") | Some("task-clock") => CpuDelta::from_nanos(sample.period),
+                _ => CpuDelta::ZERO,
+            };
+
+            fx.add_sample(thread, timestamp(sample.time), stack, cpu_delta, 1);
+        }
+    }
+
+    serde_json::to_writer(w, &fx)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stacks::Sample;
+    use serde_json::Value;
+
+    #[test]
+    fn thread_per_tid() {
+        let sample = |tid: u32, comm: &str, time: f64| Sample {
+            comm: comm.into(),
+            ..Sample::new(tid, time, &["main", "work"])
+        };
+        let profile = Profile {
+            name: "test".into(),
+            collapsed: vec![],
+            samples: Some(vec![
+                sample(1, "main", 1.0),
+                sample(2, "worker", 1.001),
+                sample(1, "main", 1.002),
+            ]),
+            context_switches: vec![],
+        };
+
+        let mut buf = vec![];
+        write(&profile, &mut buf).unwrap();
+        let json: Value = serde_json::from_slice(&buf).unwrap();
+
+        let threads = json["threads"].as_array().unwrap();
+        let names = threads
+            .iter()
+            .map(|t| {
+                (
+                    t["name"].as_str().unwrap(),
+                    t["samples"]["length"].as_u64().unwrap(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec![("main", 2), ("worker", 1)]);
+    }
+}
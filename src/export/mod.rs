@@ -0,0 +1,272 @@
+//! Converts profiles to formats of other viewers.
+
+use crate::cli_tools::recording::Recording;
+use crate::stacks::ContextSwitch;
+use crate::stacks::Sample;
+use crate::stacks::StackCounts;
+use crate::stacks::StackOptions;
+use crate::util::open_output;
+use anyhow::Context;
+use anyhow::Error;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+mod chrome;
+mod firefox;
+mod pprof;
+mod speedscope;
+
+/// Converts a profile stored by a previous run to the format of another
+/// viewer.
+///
+/// Profiles can be collapsed stacks, or raw data stored using `--save-raw`.
+#[derive(Debug, Clone, StructOpt)]
+pub struct ExportCommand {
+    /// Use sudo to read `perf.data`.
+    #[structopt(long)]
+    root: bool,
+
+    /// Output format. `speedscope`, `pprof`, `firefox` or `chrome`
+    #[structopt(long, value_name = "FORMAT")]
+    format: Format,
+
+    /// Path to the output file. Use `-` to write to stdout.
+    ///
+    /// Defaults to a file named after the format, like `profile.speedscope.json`.
+    #[structopt(short = "o", long, parse(from_os_str), value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    stacks: StackOptions,
+
+    /// The profile to convert.
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+}
+
+impl ExportCommand {
+    pub fn run(self) -> Result<(), Error> {
+        let recording = Recording::open(self.root, &self.input)
+            .with_context(|| format!("failed to load {}", self.input.display()))?;
+
+        let name = self
+            .input
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let profile = Profile::load(self.format, &recording, &self.stacks, name)?;
+
+        let format = self.format;
+        let output = self
+            .output
+            .unwrap_or_else(|| format.default_output().into());
+        format.write(&profile, &output)
+    }
+}
+
+/// Formats of other viewers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// <https://www.speedscope.app>
+    Speedscope,
+    /// Gzip-compressed `profile.proto` of <https://github.com/google/pprof>
+    Pprof,
+    /// Processed profile of <https://profiler.firefox.com>. Requires
+    /// `perf.data`.
+    Firefox,
+    /// Trace events of chrome, which can be opened using
+    /// <https://ui.perfetto.dev> or `about://tracing`. Requires `perf.data`.
+    Chrome,
+}
+
+impl Format {
+    pub fn default_output(self) -> &'static str {
+        match self {
+            Format::Speedscope => "profile.speedscope.json",
+            Format::Pprof => "profile.pb.gz",
+            Format::Firefox => "profile.firefox.json",
+            Format::Chrome => "trace.json",
+        }
+    }
+
+    /// Returns true if the format uses context switches.
+    pub fn context_switches(self) -> bool {
+        match self {
+            Format::Speedscope | Format::Pprof | Format::Firefox => false,
+            Format::Chrome => true,
+        }
+    }
+
+    /// Returns true if the format uses source files and lines of frames.
+    pub fn source_lines(self) -> bool {
+        match self {
+            Format::Speedscope | Format::Firefox | Format::Chrome => false,
+            Format::Pprof => true,
+        }
+    }
+
+    /// Writes `profile` to `output`, which may be `-` for stdout.
+    pub fn write(self, profile: &Profile, output: &Path) -> Result<(), Error> {
+        let mut w = open_output(output)?;
+
+        match self {
+            Format::Speedscope => speedscope::write(profile, &mut w),
+            Format::Pprof => pprof::write(profile, &mut w),
+            Format::Firefox => firefox::write(profile, &mut w),
+            Format::Chrome => chrome::write(profile, &mut w),
+        }
+        .with_context(|| format!("failed to write {}", output.display()))?;
+
+        w.flush()
+            .with_context(|| format!("failed to write {}", output.display()))
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "speedscope" => Ok(Format::Speedscope),
+            "pprof" => Ok(Format::Pprof),
+            "firefox" => Ok(Format::Firefox),
+            "chrome" => Ok(Format::Chrome),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+/// A profile to export.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub collapsed: Vec<u8>,
+    /// Samples with timestamps, if the profiler recorded them.
+    pub samples: Option<Vec<Sample>>,
+    /// Empty if the profiler did not record them.
+    pub context_switches: Vec<ContextSwitch>,
+}
+
+impl Profile {
+    /// Creates a profile for `format` from `collapsed`, which is not
+    /// transformed by `stacks` yet.
+    ///
+    /// Samples and context switches are read from `recording`, if the format
+    /// uses them.
+    pub fn new(
+        format: Format,
+        name: String,
+        collapsed: &[u8],
+        recording: Option<&Recording>,
+        stacks: &StackOptions,
+    ) -> Result<Self, Error> {
+        let samples = match recording {
+            Some(recording) => recording
+                .samples(format.source_lines())
+                .context("failed to read samples")?,
+            None => None,
+        };
+
+        Profile::with_samples(format, name, collapsed, samples, recording, stacks)
+    }
+
+    /// Loads stacks from `recording` for `format`, and applies `stacks` to
+    /// them.
+    ///
+    /// If the recording has samples, stacks are collapsed from them, so the
+    /// recording is read once.
+    pub fn load(
+        format: Format,
+        recording: &Recording,
+        stacks: &StackOptions,
+        name: String,
+    ) -> Result<Self, Error> {
+        let samples = recording
+            .samples(format.source_lines())
+            .context("failed to read samples")?;
+        let collapsed = match &samples {
+            Some(samples) => collapse_samples(samples),
+            None => recording.to_collapsed()?,
+        };
+
+        Profile::with_samples(format, name, &collapsed, samples, Some(recording), stacks)
+    }
+
+    fn with_samples(
+        format: Format,
+        name: String,
+        collapsed: &[u8],
+        samples: Option<Vec<Sample>>,
+        recording: Option<&Recording>,
+        stacks: &StackOptions,
+    ) -> Result<Self, Error> {
+        let mut profile = Profile {
+            name,
+            collapsed: stacks.apply(collapsed),
+            samples: samples.map(|samples| stacks.apply_samples(samples)),
+            context_switches: vec![],
+        };
+
+        if let (Some(recording), true) = (recording, format.context_switches()) {
+            profile.context_switches = recording
+                .context_switches()
+                .context("failed to read context switches")?
+                .unwrap_or_default();
+        }
+
+        Ok(profile)
+    }
+}
+
+/// Collapses stacks of samples, like collapsing `perf.data`.
+///
+/// Like inferno, only samples of the first event are collapsed.
+fn collapse_samples(samples: &[Sample]) -> Vec<u8> {
+    let mut counts = StackCounts::default();
+    if let Some(first) = samples.first() {
+        for sample in samples.iter().filter(|s| s.event == first.event) {
+            counts.add_sample(sample, false);
+        }
+    }
+    counts.into_collapsed()
+}
+
+/// Used if the sampling interval cannot be estimated.
+const DEFAULT_INTERVAL: f64 = 0.001;
+
+/// Groups samples by `(pid, tid)`, and sorts them by timestamps.
+pub(crate) fn group_by_thread<'a, I>(samples: I) -> BTreeMap<(u32, u32), Vec<&'a Sample>>
+where
+    I: IntoIterator<Item = &'a Sample>,
+{
+    let mut threads = BTreeMap::<_, Vec<&Sample>>::new();
+    for sample in samples {
+        threads
+            .entry((sample.pid, sample.tid))
+            .or_default()
+            .push(sample);
+    }
+    for samples in threads.values_mut() {
+        samples.sort_by(|a, b| a.time.total_cmp(&b.time));
+    }
+    threads
+}
+
+/// Returns the median of intervals between samples of a thread, in seconds.
+pub(crate) fn sampling_interval(threads: &BTreeMap<(u32, u32), Vec<&Sample>>) -> f64 {
+    let mut intervals = threads
+        .values()
+        .flat_map(|samples| samples.windows(2).map(|w| w[1].time - w[0].time))
+        .filter(|&interval| interval > 0.0)
+        .collect::<Vec<_>>();
+    if intervals.is_empty() {
+        return DEFAULT_INTERVAL;
+    }
+
+    intervals.sort_by(f64::total_cmp);
+    intervals[(intervals.len() - 1) / 2]
+}
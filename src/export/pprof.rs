@@ -0,0 +1,549 @@
+//! https://github.com/google/pprof/blob/main/proto/profile.proto
+
+use super::Profile;
+use crate::stacks::parse_line;
+use crate::stacks::Frame;
+use anyhow::Error;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use prost::Message;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Writes a gzip-compressed `profile.proto`.
+///
+/// Samples of perf have an additional value for each event, like `cycles`.
+/// Stacks of dtrace or collapsed stacks are converted to samples with only
+/// counts, and modules of frames like `libc.so`malloc` are used as mappings.
+pub(super) fn write(profile: &Profile, w: &mut dyn Write) -> Result<(), Error> {
+    let mut builder = Builder::default();
+
+    match &profile.samples {
+        Some(samples) if !samples.is_empty() => {
+            // Events differing only by modifiers, like `cycles:u` and
+            // `cycles:ppp`, are merged, as they count the same thing.
+            let mut events = vec![];
+            for sample in samples {
+                if let Some(event) = sample.event.as_deref().map(event_name) {
+                    if !events.contains(&event) {
+                        events.push(event);
+                    }
+                }
+            }
+
+            let samples_type = builder.value_type("samples", "count");
+            builder.sample_types.push(samples_type);
+            for event in &events {
+                let value_type = builder.value_type(event, event_unit(event));
+                builder.sample_types.push(value_type);
+            }
+
+            for sample in samples {
+                let mut values = vec![0; 1 + events.len()];
+                values[0] = 1;
+                if let Some(event) = sample.event.as_deref().map(event_name) {
+                    let idx = events.iter().position(|&e| e == event).unwrap();
+                    values[1 + idx] = sample.period as i64;
+                }
+
+                builder.add_sample(&sample.stack, values);
+            }
+
+            let start = samples.iter().map(|s| s.time).fold(f64::INFINITY, f64::min);
+            let end = samples.iter().map(|s| s.time).fold(0.0, f64::max);
+            builder.duration_nanos = ((end - start) * 1e9) as i64;
+        }
+        _ => {
+            let samples_type = builder.value_type("samples", "count");
+            builder.sample_types.push(samples_type);
+
+            let collapsed = String::from_utf8_lossy(&profile.collapsed);
+            for (stack, count) in collapsed.lines().filter_map(parse_line) {
+                let stack = stack.split(';').map(parse_frame).collect::<Vec<_>>();
+                builder.add_sample(&stack, vec![count as i64]);
+            }
+        }
+    }
+
+    let mut encoder = GzEncoder::new(w, Compression::default());
+    encoder.write_all(&builder.finish().encode_to_vec())?;
+    encoder.finish()?;
+
+    Ok(())
+}
+
+/// Strips modifiers of a perf event, like `:u` of `cycles:u`.
+fn event_name(event: &str) -> &str {
+    event.split(':').next().unwrap_or(event)
+}
+
+/// Unit of the period of a perf event.
+fn event_unit(event: &str) -> &'static str {
+    match event {
+        "cpu-clock" | "task-clock" => "nanoseconds",
+        _ => "count",
+    }
+}
+
+/// Splits the module of dtrace frames like `libc.so`malloc`.
+fn parse_frame(frame: &str) -> Frame {
+    match frame.split_once('`') {
+        Some((module, name)) => Frame {
+            name: name.to_string(),
+            module: Some(module.to_string()),
+            ..Default::default()
+        },
+        None => Frame::new(frame),
+    }
+}
+
+#[derive(Default)]
+struct Builder {
+    strings: Vec<String>,
+    string_ids: HashMap<String, i64>,
+
+    sample_types: Vec<ValueType>,
+    /// Values of samples with same locations are summed.
+    samples: HashMap<Vec<u64>, Vec<i64>>,
+    sample_order: Vec<Vec<u64>>,
+
+    mappings: Vec<Mapping>,
+    mapping_ids: HashMap<String, u64>,
+    locations: Vec<Location>,
+    location_ids: HashMap<Vec<Frame>, u64>,
+    functions: Vec<Function>,
+    function_ids: HashMap<(String, Option<String>), u64>,
+
+    duration_nanos: i64,
+}
+
+impl Builder {
+    fn string(&mut self, s: &str) -> i64 {
+        if self.strings.is_empty() {
+            // The first string must be empty.
+            self.strings.push(String::new());
+            self.string_ids.insert(String::new(), 0);
+        }
+
+        if let Some(&id) = self.string_ids.get(s) {
+            return id;
+        }
+
+        let id = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.string_ids.insert(s.to_string(), id);
+        id
+    }
+
+    fn value_type(&mut self, ty: &str, unit: &str) -> ValueType {
+        ValueType {
+            r#type: self.string(ty),
+            unit: self.string(unit),
+        }
+    }
+
+    /// `stack` is from the root to the leaf.
+    fn add_sample(&mut self, stack: &[Frame], values: Vec<i64>) {
+        // pprof expects the leaf first. Frames of an address, which are
+        // functions inlined at the address, share a location.
+        let mut locations = vec![];
+        let mut end = stack.len();
+        while end > 0 {
+            let leaf = &stack[end - 1];
+            let mut start = end - 1;
+            while leaf.address.is_some()
+                && start > 0
+                && stack[start - 1].address == leaf.address
+                && stack[start - 1].module == leaf.module
+            {
+                start -= 1;
+            }
+
+            locations.push(self.location(&stack[start..end]));
+            end = start;
+        }
+
+        match self.samples.get_mut(&locations) {
+            Some(sum) => {
+                for (sum, value) in sum.iter_mut().zip(values) {
+                    *sum += value;
+                }
+            }
+            None => {
+                self.sample_order.push(locations.clone());
+                self.samples.insert(locations, values);
+            }
+        }
+    }
+
+    /// `frames` are from the outermost function to the innermost inlined
+    /// function, and have the same address.
+    fn location(&mut self, frames: &[Frame]) -> u64 {
+        if let Some(&id) = self.location_ids.get(frames) {
+            return id;
+        }
+
+        let frame = &frames[0];
+        let mapping_id = match &frame.module {
+            Some(module) => self.mapping(module),
+            None => 0,
+        };
+        if mapping_id != 0 && frames.len() > 1 {
+            self.mappings[mapping_id as usize - 1].has_inline_frames = true;
+        }
+
+        // Lines are from the innermost function.
+        let line = frames
+            .iter()
+            .rev()
+            .map(|frame| Line {
+                function_id: self.function(frame),
+                line: frame.line.map(i64::from).unwrap_or_default(),
+            })
+            .collect();
+
+        let id = self.locations.len() as u64 + 1;
+        self.locations.push(Location {
+            id,
+            mapping_id,
+            address: frame.address.unwrap_or_default(),
+            line,
+            is_folded: false,
+        });
+        self.location_ids.insert(frames.to_vec(), id);
+        id
+    }
+
+    /// Address ranges of mappings are left zero, as frames are symbolized
+    /// already and samples do not know where modules are mapped.
+    fn mapping(&mut self, module: &str) -> u64 {
+        if let Some(&id) = self.mapping_ids.get(module) {
+            return id;
+        }
+
+        let id = self.mappings.len() as u64 + 1;
+        let filename = self.string(module);
+        self.mappings.push(Mapping {
+            id,
+            filename,
+            has_functions: true,
+            ..Default::default()
+        });
+        self.mapping_ids.insert(module.to_string(), id);
+        id
+    }
+
+    fn function(&mut self, frame: &Frame) -> u64 {
+        let key = (frame.name.clone(), frame.file.clone());
+        if let Some(&id) = self.function_ids.get(&key) {
+            return id;
+        }
+
+        if let Some(mapping_id) = frame.module.as_ref().map(|m| self.mapping_ids[m]) {
+            let mapping = &mut self.mappings[mapping_id as usize - 1];
+            if frame.file.is_some() {
+                mapping.has_filenames = true;
+            }
+            if frame.line.is_some() {
+                mapping.has_line_numbers = true;
+            }
+        }
+
+        let id = self.functions.len() as u64 + 1;
+        let name = self.string(&frame.name);
+        let filename = match &frame.file {
+            Some(file) => self.string(file),
+            None => 0,
+        };
+        self.functions.push(Function {
+            id,
+            name,
+            system_name: name,
+            filename,
+            start_line: 0,
+        });
+        self.function_ids.insert(key, id);
+        id
+    }
+
+    fn finish(mut self) -> PprofProfile {
+        // Make sure the empty string exists.
+        self.string("");
+
+        let mut samples = self.samples;
+        let sample = self
+            .sample_order
+            .into_iter()
+            .map(|location_id| {
+                let value = samples.remove(&location_id).unwrap();
+                Sample {
+                    location_id,
+                    value,
+                    label: vec![],
+                }
+            })
+            .collect();
+
+        let default_sample_type = self
+            .sample_types
+            .last()
+            .map(|ty| ty.r#type)
+            .unwrap_or_default();
+
+        PprofProfile {
+            period_type: self.sample_types.last().cloned(),
+            sample_type: self.sample_types,
+            sample,
+            mapping: self.mappings,
+            location: self.locations,
+            function: self.functions,
+            string_table: self.strings,
+            duration_nanos: self.duration_nanos,
+            default_sample_type,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct PprofProfile {
+    #[prost(message, repeated, tag = "1")]
+    sample_type: Vec<ValueType>,
+    #[prost(message, repeated, tag = "2")]
+    sample: Vec<Sample>,
+    #[prost(message, repeated, tag = "3")]
+    mapping: Vec<Mapping>,
+    #[prost(message, repeated, tag = "4")]
+    location: Vec<Location>,
+    #[prost(message, repeated, tag = "5")]
+    function: Vec<Function>,
+    #[prost(string, repeated, tag = "6")]
+    string_table: Vec<String>,
+    #[prost(int64, tag = "7")]
+    drop_frames: i64,
+    #[prost(int64, tag = "8")]
+    keep_frames: i64,
+    #[prost(int64, tag = "9")]
+    time_nanos: i64,
+    #[prost(int64, tag = "10")]
+    duration_nanos: i64,
+    #[prost(message, optional, tag = "11")]
+    period_type: Option<ValueType>,
+    #[prost(int64, tag = "12")]
+    period: i64,
+    #[prost(int64, repeated, tag = "13")]
+    comment: Vec<i64>,
+    #[prost(int64, tag = "14")]
+    default_sample_type: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ValueType {
+    #[prost(int64, tag = "1")]
+    r#type: i64,
+    #[prost(int64, tag = "2")]
+    unit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(uint64, repeated, tag = "1")]
+    location_id: Vec<u64>,
+    #[prost(int64, repeated, tag = "2")]
+    value: Vec<i64>,
+    #[prost(message, repeated, tag = "3")]
+    label: Vec<Label>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(int64, tag = "1")]
+    key: i64,
+    #[prost(int64, tag = "2")]
+    str: i64,
+    #[prost(int64, tag = "3")]
+    num: i64,
+    #[prost(int64, tag = "4")]
+    num_unit: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Mapping {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(uint64, tag = "2")]
+    memory_start: u64,
+    #[prost(uint64, tag = "3")]
+    memory_limit: u64,
+    #[prost(uint64, tag = "4")]
+    file_offset: u64,
+    #[prost(int64, tag = "5")]
+    filename: i64,
+    #[prost(int64, tag = "6")]
+    build_id: i64,
+    #[prost(bool, tag = "7")]
+    has_functions: bool,
+    #[prost(bool, tag = "8")]
+    has_filenames: bool,
+    #[prost(bool, tag = "9")]
+    has_line_numbers: bool,
+    #[prost(bool, tag = "10")]
+    has_inline_frames: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Location {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(uint64, tag = "2")]
+    mapping_id: u64,
+    #[prost(uint64, tag = "3")]
+    address: u64,
+    #[prost(message, repeated, tag = "4")]
+    line: Vec<Line>,
+    #[prost(bool, tag = "5")]
+    is_folded: bool,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Line {
+    #[prost(uint64, tag = "1")]
+    function_id: u64,
+    #[prost(int64, tag = "2")]
+    line: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Function {
+    #[prost(uint64, tag = "1")]
+    id: u64,
+    #[prost(int64, tag = "2")]
+    name: i64,
+    #[prost(int64, tag = "3")]
+    system_name: i64,
+    #[prost(int64, tag = "4")]
+    filename: i64,
+    #[prost(int64, tag = "5")]
+    start_line: i64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn dtrace_stacks() {
+        let profile = Profile {
+            name: "test".into(),
+            collapsed: b"a.out`main;libc.so`malloc 3\na.out`main 2\n".to_vec(),
+            samples: None,
+            context_switches: vec![],
+        };
+
+        let mut buf = vec![];
+        write(&profile, &mut buf).unwrap();
+        let mut decoded = vec![];
+        GzDecoder::new(&*buf).read_to_end(&mut decoded).unwrap();
+        let p = PprofProfile::decode(&*decoded).unwrap();
+
+        let s = |id: i64| &*p.string_table[id as usize];
+        assert_eq!(s(0), "");
+        assert_eq!(s(p.sample_type[0].r#type), "samples");
+
+        assert_eq!(p.sample.len(), 2);
+        assert_eq!(p.sample[0].location_id, vec![1, 2]);
+        assert_eq!(p.sample[0].value, vec![3]);
+
+        assert_eq!(
+            p.mapping.iter().map(|m| s(m.filename)).collect::<Vec<_>>(),
+            vec!["libc.so", "a.out"]
+        );
+        assert_eq!(
+            s(p.function[p.location[0].line[0].function_id as usize - 1].name),
+            "malloc"
+        );
+    }
+
+    #[test]
+    fn modifiers_of_events_are_merged() {
+        let sample = |event: &str, period: u64| crate::stacks::Sample {
+            event: Some(event.into()),
+            period,
+            ..crate::stacks::Sample::new(1, 1.0, &["main"])
+        };
+        let profile = Profile {
+            name: "test".into(),
+            collapsed: vec![],
+            samples: Some(vec![
+                sample("cycles:u", 10),
+                sample("cycles:ppp", 20),
+                sample("cpu-clock", 5),
+            ]),
+            context_switches: vec![],
+        };
+
+        let mut buf = vec![];
+        write(&profile, &mut buf).unwrap();
+        let mut decoded = vec![];
+        GzDecoder::new(&*buf).read_to_end(&mut decoded).unwrap();
+        let p = PprofProfile::decode(&*decoded).unwrap();
+
+        let s = |id: i64| &*p.string_table[id as usize];
+        assert_eq!(
+            p.sample_type
+                .iter()
+                .map(|t| s(t.r#type))
+                .collect::<Vec<_>>(),
+            vec!["samples", "cycles", "cpu-clock"]
+        );
+        assert_eq!(p.sample[0].value, vec![3, 30, 5]);
+    }
+
+    #[test]
+    fn inlined_functions_share_locations() {
+        let frame = |name: &str, address: u64, line: u32| Frame {
+            name: name.into(),
+            module: Some("a.out".into()),
+            address: Some(address),
+            file: Some("src/main.rs".into()),
+            line: Some(line),
+        };
+        let profile = Profile {
+            name: "test".into(),
+            collapsed: vec![],
+            samples: Some(vec![crate::stacks::Sample {
+                stack: vec![
+                    frame("main", 0x10, 1),
+                    frame("parse", 0x20, 2),
+                    frame("lex", 0x20, 3),
+                ],
+                ..crate::stacks::Sample::new(1, 1.0, &[])
+            }]),
+            context_switches: vec![],
+        };
+
+        let mut buf = vec![];
+        write(&profile, &mut buf).unwrap();
+        let mut decoded = vec![];
+        GzDecoder::new(&*buf).read_to_end(&mut decoded).unwrap();
+        let p = PprofProfile::decode(&*decoded).unwrap();
+
+        let s = |id: i64| &*p.string_table[id as usize];
+        let lines = |id: u64| {
+            p.location[id as usize - 1]
+                .line
+                .iter()
+                .map(|l| (s(p.function[l.function_id as usize - 1].name), l.line))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(p.sample[0].location_id.len(), 2);
+        assert_eq!(
+            lines(p.sample[0].location_id[0]),
+            vec![("lex", 3), ("parse", 2)]
+        );
+        assert_eq!(lines(p.sample[0].location_id[1]), vec![("main", 1)]);
+        assert!(p.mapping[0].has_inline_frames);
+    }
+}
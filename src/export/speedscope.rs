@@ -0,0 +1,187 @@
+//! https://github.com/jlfwong/speedscope/wiki/Importing-from-custom-sources
+
+use super::group_by_thread;
+use super::sampling_interval;
+use super::Profile;
+use crate::stacks::parse_line;
+use crate::stacks::Sample;
+use anyhow::Error;
+use serde_json::json;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::io::Write;
+
+/// Writes a sampled profile, or an evented profile per thread if samples have
+/// timestamps.
+pub(super) fn write(profile: &Profile, w: &mut dyn Write) -> Result<(), Error> {
+    let mut frames = Frames::default();
+
+    let profiles = match &profile.samples {
+        Some(samples) if !samples.is_empty() => evented(&mut frames, samples),
+        _ => vec![sampled(&mut frames, &profile.name, &profile.collapsed)],
+    };
+
+    let file = json!({
+        "$schema": "https://www.speedscope.app/file-format-schema.json",
+        "shared": {
+            "frames": frames
+                .names
+                .iter()
+                .map(|name| json!({ "name": name }))
+                .collect::<Vec<_>>(),
+        },
+        "profiles": profiles,
+        "name": profile.name,
+        "activeProfileIndex": 0,
+        "exporter": concat!("cargo-profile ", env!("CARGO_PKG_VERSION")),
+    });
+
+    serde_json::to_writer(w, &file)?;
+
+    Ok(())
+}
+
+#[derive(Default)]
+struct Frames {
+    indices: HashMap<String, usize>,
+    names: Vec<String>,
+}
+
+impl Frames {
+    fn index(&mut self, name: &str) -> usize {
+        if let Some(&idx) = self.indices.get(name) {
+            return idx;
+        }
+
+        let idx = self.names.len();
+        self.names.push(name.to_string());
+        self.indices.insert(name.to_string(), idx);
+        idx
+    }
+}
+
+fn sampled(frames: &mut Frames, name: &str, collapsed: &[u8]) -> Value {
+    let collapsed = String::from_utf8_lossy(collapsed);
+
+    let mut samples = vec![];
+    let mut weights = vec![];
+    for (stack, count) in collapsed.lines().filter_map(parse_line) {
+        samples.push(
+            stack
+                .split(';')
+                .map(|frame| frames.index(frame))
+                .collect::<Vec<_>>(),
+        );
+        weights.push(count);
+    }
+    let total = weights.iter().sum::<usize>();
+
+    json!({
+        "type": "sampled",
+        "name": name,
+        "unit": "none",
+        "startValue": 0,
+        "endValue": total,
+        "samples": samples,
+        "weights": weights,
+    })
+}
+
+/// Creates an evented profile for each thread.
+///
+/// A sample lasts until the next sample of the thread, or for the sampling
+/// interval if the thread is not sampled for a while.
+fn evented(frames: &mut Frames, samples: &[Sample]) -> Vec<Value> {
+    let threads = group_by_thread(samples);
+    let interval = sampling_interval(&threads);
+
+    threads
+        .values()
+        .map(|samples| {
+            let mut events = vec![];
+            let mut open = Vec::<usize>::new();
+
+            for (i, sample) in samples.iter().enumerate() {
+                let stack = sample
+                    .stack
+                    .iter()
+                    .map(|frame| frames.index(&frame.name))
+                    .collect::<Vec<_>>();
+
+                let common = open.iter().zip(&stack).take_while(|(a, b)| a == b).count();
+                close(&mut events, &mut open, common, sample.time);
+
+                for &frame in &stack[common..] {
+                    events.push(json!({ "type": "O", "frame": frame, "at": sample.time }));
+                    open.push(frame);
+                }
+
+                let is_idle = match samples.get(i + 1) {
+                    Some(next) => next.time > sample.time + 2.0 * interval,
+                    None => true,
+                };
+                if is_idle {
+                    close(&mut events, &mut open, 0, sample.time + interval);
+                }
+            }
+
+            let last = samples.last().unwrap();
+            json!({
+                "type": "evented",
+                "name": format!("{} ({})", last.comm, last.tid),
+                "unit": "seconds",
+                "startValue": samples[0].time,
+                "endValue": last.time + interval,
+                "events": events,
+            })
+        })
+        .collect()
+}
+
+/// Closes frames of `open` until `len` frames are left.
+fn close(events: &mut Vec<Value>, open: &mut Vec<usize>, len: usize, at: f64) {
+    while open.len() > len {
+        let frame = open.pop().unwrap();
+        events.push(json!({ "type": "C", "frame": frame, "at": at }));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evented_per_thread() {
+        let mut frames = Frames::default();
+        let profiles = evented(
+            &mut frames,
+            &[
+                Sample::new(1, 1.0, &["main", "a"]),
+                Sample::new(2, 1.0, &["main"]),
+                Sample::new(1, 2.0, &["main", "b"]),
+                Sample::new(1, 10.0, &["main"]),
+            ],
+        );
+
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(frames.names, vec!["main", "a", "b"]);
+
+        let events = profiles[0]["events"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|e| {
+                format!(
+                    "{}{}@{}",
+                    e["type"].as_str().unwrap(),
+                    e["frame"],
+                    e["at"].as_f64().unwrap()
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            events,
+            vec!["O0@1", "O1@1", "C1@2", "O2@2", "C2@3", "C0@3", "O0@10", "C0@11"]
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use crate::cli_tools::recording::load_collapsed;
+use crate::flamegraph::render::RenderOptions;
+use crate::stacks::StackOptions;
+use anyhow::Context;
+use anyhow::Error;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+/// Creates a differential flamegraph from two profiles.
+///
+/// The flamegraph is drawn using the second profile. Frames which got slower
+/// are colored red and frames which got faster are colored blue.
+///
+/// Profiles can be collapsed stacks, or raw data stored using `--save-raw`.
+#[derive(Debug, Clone, StructOpt)]
+pub struct DiffCommand {
+    /// Use sudo to read `perf.data`.
+    #[structopt(long)]
+    root: bool,
+
+    /// Scale the sample counts of the first profile to match the second.
+    ///
+    /// Without this, everything is red if the second run took more samples.
+    #[structopt(long)]
+    normalize: bool,
+
+    /// Replace addresses like `0x45ef2173` with `0x...`.
+    #[structopt(long)]
+    strip_hex: bool,
+
+    /// Draw the flamegraph using the first profile instead, which shows code
+    /// paths missing from the second profile.
+    #[structopt(long)]
+    negate: bool,
+
+    #[structopt(flatten)]
+    stacks: StackOptions,
+
+    #[structopt(flatten)]
+    render: RenderOptions,
+
+    /// The profile of the baseline.
+    #[structopt(parse(from_os_str))]
+    before: PathBuf,
+
+    /// The profile to compare with the baseline.
+    #[structopt(parse(from_os_str))]
+    after: PathBuf,
+}
+
+impl DiffCommand {
+    pub fn run(self) -> Result<(), Error> {
+        let before = load_collapsed(self.root, &self.before)
+            .with_context(|| format!("failed to load {}", self.before.display()))?;
+        let after = load_collapsed(self.root, &self.after)
+            .with_context(|| format!("failed to load {}", self.after.display()))?;
+
+        let before = self.stacks.apply(&before);
+        let after = self.stacks.apply(&after);
+
+        let (before, after) = if self.negate {
+            (after, before)
+        } else {
+            (before, after)
+        };
+
+        let opts = inferno::differential::Options {
+            normalize: self.normalize,
+            strip_hex: self.strip_hex,
+        };
+
+        let mut folded = vec![];
+        inferno::differential::from_readers(opts, &*before, &*after, &mut folded)
+            .context("failed to fold differential stacks")?;
+
+        let negate = self.negate;
+        let output = self.render.output_or("flamegraph.svg");
+        self.render
+            .render_with(&output, |opts, writer| {
+                opts.negate_differentials = negate;
+                inferno::flamegraph::from_reader(opts, &*folded, writer)
+            })
+            .context("failed to render differential flamegraph")
+    }
+}
@@ -1 +0,0 @@
-pub(super) const DTRACE_OUTPUT_FILENAME: &str = "cargo-profile-flamegraph.stacks";
@@ -1,107 +1,643 @@
 use crate::cargo::compile;
+use crate::cargo::BinFile;
 use crate::cargo::CargoTarget;
-use crate::cli_tools::dtrace::make_dtrace_command;
-use crate::cli_tools::profiler::run_profiler;
+use crate::cli_tools::profiler::profile;
+use crate::cli_tools::profiler::AttachOptions;
+use crate::cli_tools::profiler::ProfileTarget;
+use crate::cli_tools::profiler::RecordOptions;
+use crate::cli_tools::recording::Recording;
+use crate::export::Format;
+use crate::export::Profile;
+use crate::flamegraph::diff::DiffCommand;
+use crate::flamegraph::render::RenderOptions;
+use crate::flamegraph::timeline::TimelineOptions;
+use crate::stacks::offcpu::Clock;
+use crate::stacks::split_threads;
+use crate::stacks::StackOptions;
+use crate::stacks::ThreadStacks;
+use crate::util::open_output;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
-use std::fs::OpenOptions;
-use std::io::BufWriter;
-use std::io::Cursor;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
 use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
 use structopt::StructOpt;
-use tempdir::TempDir;
 
-mod linux;
-mod macos;
+mod diff;
+mod render;
+mod timeline;
 
 /// Creates a flamegraph for given target.
+///
+/// If cargo produces multiple binaries, a flamegraph is created for each
+/// binary along with an html file listing them.
 #[derive(Debug, Clone, StructOpt)]
 pub struct FlameGraphCommand {
     /// Use sudo.
     #[structopt(long)]
     root: bool,
 
+    /// Store the raw data recorded by the profiler (`perf.data` on linux,
+    /// dtrace stacks on macos) at this path instead of a temporary directory.
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    save_raw: Option<PathBuf>,
+
+    /// Store the collapsed stacks at this path.
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    save_folded: Option<PathBuf>,
+
+    /// Render the flamegraph from collapsed stacks, `perf.data` or dtrace
+    /// stacks stored by a previous run, instead of compiling and running
+    /// the target.
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    from: Option<PathBuf>,
+
+    /// Merge stacks of all binaries into one flamegraph, instead of creating
+    /// a flamegraph per binary.
+    #[structopt(long)]
+    merge: bool,
+
+    /// Weigh stacks by the time threads are blocked off the cpu, like waiting
+    /// for locks or io, instead of sampling stacks running on the cpu.
+    ///
+    /// Requires perf, and usually `--root` to record scheduler switches.
+    #[structopt(long, conflicts_with = "wall-clock")]
+    off_cpu: bool,
+
+    /// Weigh stacks by the time spent on and off the cpu. Stacks blocked off
+    /// the cpu end with an `[off-cpu]` frame.
+    ///
+    /// Requires perf, and usually `--root` to record scheduler switches.
+    #[structopt(long)]
+    wall_clock: bool,
+
+    /// Create a flamegraph for each thread name, so thread pools can be
+    /// compared. With `--merge`, thread names are root frames instead.
+    /// Requires perf.
+    #[structopt(long)]
+    per_thread: bool,
+
+    /// Output format. `svg`, `speedscope` for https://www.speedscope.app,
+    /// `pprof` for `go tool pprof`, `firefox` for https://profiler.firefox.com
+    /// or `chrome` for https://ui.perfetto.dev
+    ///
+    /// If `perf.data` is available, speedscope profiles contain a timeline
+    /// per thread.
+    #[structopt(long, value_name = "FORMAT", default_value = "svg")]
+    format: OutputFormat,
+
+    #[structopt(flatten)]
+    attach: AttachOptions,
+
+    #[structopt(flatten)]
+    record: RecordOptions,
+
+    #[structopt(flatten)]
+    stacks: StackOptions,
+
+    #[structopt(flatten)]
+    render: RenderOptions,
+
+    #[structopt(flatten)]
+    timeline: TimelineOptions,
+
+    #[structopt(subcommand)]
+    cmd: Option<FlameGraphSubCommand>,
+
     /// Compile library
     #[structopt(flatten)]
     target: CargoTarget,
 }
 
+#[derive(Debug, Clone, StructOpt)]
+pub enum FlameGraphSubCommand {
+    Diff(DiffCommand),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum OutputFormat {
+    Svg,
+    Export(Format),
+}
+
+impl OutputFormat {
+    fn default_output(self) -> &'static str {
+        match self {
+            OutputFormat::Svg => "flamegraph.svg",
+            OutputFormat::Export(format) => format.default_output(),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "svg" => Ok(OutputFormat::Svg),
+            _ => s.parse().map(OutputFormat::Export),
+        }
+    }
+}
+
 impl FlameGraphCommand {
     pub fn run(self) -> Result<(), Error> {
-        let Self { root, target } = self;
+        let Self {
+            root,
+            save_raw,
+            save_folded,
+            from,
+            merge,
+            per_thread,
+            off_cpu,
+            wall_clock,
+            format,
+            attach,
+            mut record,
+            stacks,
+            render,
+            timeline,
+            cmd,
+            target,
+        } = self;
 
-        let binaries = compile(&target).context("cargo execution failed")?;
+        if let Some(cmd) = cmd {
+            return match cmd {
+                FlameGraphSubCommand::Diff(cmd) => cmd.run(),
+            };
+        }
+
+        if timeline.is_enabled() {
+            if let OutputFormat::Export(..) = format {
+                bail!(
+                    "`--flame-chart`, `--from-time`, `--to-time` and `--cpu-usage` can be used \
+                     only with svg"
+                );
+            }
+            if merge {
+                bail!(
+                    "`--flame-chart`, `--from-time`, `--to-time` and `--cpu-usage` cannot be \
+                     used with `--merge`"
+                );
+            }
+        }
+
+        if per_thread {
+            if let OutputFormat::Export(..) = format {
+                bail!(
+                    "`--per-thread` can be used only with svg. Other viewers show threads by \
+                     themselves"
+                );
+            }
+        }
+
+        let clock = if off_cpu {
+            Clock::OffCpu
+        } else if wall_clock {
+            Clock::Wall
+        } else {
+            Clock::OnCpu
+        };
+        if clock != Clock::OnCpu {
+            if let OutputFormat::Export(..) = format {
+                bail!("`--off-cpu` and `--wall-clock` can be used only with svg");
+            }
+            if timeline.is_enabled() {
+                bail!(
+                    "`--off-cpu` and `--wall-clock` cannot be used with `--flame-chart`, \
+                     `--from-time`, `--to-time` and `--cpu-usage`"
+                );
+            }
+            record.sched_switch = true;
+            record.switch_events = true;
+        }
+
+        let output = render.output_or(format.default_output());
+        let writer = Writer {
+            format,
+            clock,
+            per_thread,
+            stacks,
+            render,
+            timeline,
+        };
+
+        let recording = if let Some(from) = &from {
+            let recording = Recording::open(root, from)
+                .with_context(|| format!("failed to load {}", from.display()))?;
+            let name = from
+                .file_name()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Some((recording, name))
+        } else if let Some(profilee) = attach.target() {
+            let recording = profile(root, profilee, &record, save_raw.as_deref())?;
+            Some((
+                recording,
+                format!("process {}", attach.pid.unwrap_or_default()),
+            ))
+        } else {
+            None
+        };
 
-        if binaries.len() != 1 {
-            // TODO
+        if per_thread && !merge && output == Path::new("-") {
             bail!(
-                "Currently cargo profile flaemgraph only supports single binary, but cargo \
-                 produced {} binaries",
-                binaries.len()
+                "`--per-thread` creates a flamegraph per thread, so flamegraphs cannot be written \
+                 to stdout. Pass `--merge` to create one flamegraph"
             )
         }
 
-        for binary in &binaries {
-            let dir = TempDir::new("cargo-profile").context("failed to create temp dir")?;
+        if let Some((recording, name)) = recording {
+            let collapsed = writer.collapse(&recording)?;
 
-            //
-            eprintln!("Profiling {}", binary.path.display());
+            if let Some(path) = &save_folded {
+                save(path, &collapsed.stacks)?;
+            }
 
-            let cmd = if cfg!(target_os = "macos") {
-                make_dtrace_command(
-                    root,
-                    binary,
-                    &dir.path().join(self::macos::DTRACE_OUTPUT_FILENAME),
-                    None,
+            if merge {
+                let roots = per_thread as usize;
+                return writer.write(
+                    Some(&recording),
                     None,
-                    target.args(),
-                )?
-            } else if cfg!(target_os = "linux") {
-                self::linux::perf(root, binary, None, target.args())?
-            } else {
-                bail!("cargo profile flamegraph currently supports only `linux` and `macos`")
-            };
+                    &collapsed.stacks,
+                    roots,
+                    &output,
+                    &name,
+                );
+            }
 
-            run_profiler(cmd).context("failed to profile program")?;
+            let outputs =
+                writer.write_all(&recording, &collapsed, &output, &name, String::new())?;
+            if per_thread {
+                write_index(&output, &outputs)?;
+            }
+            return Ok(());
+        }
+
+        let binaries = compile(&target).context("cargo execution failed")?;
+        let is_single = binaries.len() == 1;
 
-            let collapsed: Vec<u8> = if cfg!(target_os = "macos") {
-                crate::cli_tools::dtrace::to_collapsed(
-                    &dir.path().join(self::macos::DTRACE_OUTPUT_FILENAME),
-                )?
-            } else if cfg!(target_os = "linux") {
-                self::linux::to_collapsed()?
+        if !is_single && !merge && output == Path::new("-") {
+            bail!(
+                "cargo produced {} binaries, so flamegraphs cannot be written to stdout. Pass \
+                 `--merge` to create one flamegraph",
+                binaries.len()
+            )
+        }
+
+        let names = unique_names(&binaries);
+        let mut merged = vec![];
+        let mut outputs = vec![];
+
+        let path_for = |path: &Path, name: &str| {
+            if is_single {
+                path.to_path_buf()
             } else {
-                bail!("cargo profile flamegraph currently supports only `linux` and `macos`")
-            };
-            let mut collapsed = Cursor::new(collapsed);
+                with_suffix(path, name)
+            }
+        };
 
-            // TODO
-            let flamegraph_file_path = Path::new("flamegraph.svg");
-            let flamegraph_file = OpenOptions::new()
-                .write(true)
-                .truncate(true)
-                .create(true)
-                .open(&flamegraph_file_path)
-                .context("unable to create flamegraph.svg output file")?;
+        for (binary, name) in binaries.iter().zip(&names) {
+            let save_raw = save_raw.as_ref().map(|path| path_for(path, name));
 
-            let flamegraph_writer = BufWriter::new(flamegraph_file);
+            let recording = profile(
+                root,
+                ProfileTarget::Binary {
+                    file: binary,
+                    args: target.args(),
+                },
+                &record,
+                save_raw.as_deref(),
+            )?;
+            let collapsed = writer.collapse(&recording)?;
 
-            let mut flamegraph_options = inferno::flamegraph::Options::default();
+            if merge {
+                prepend_frame(&mut merged, name, &collapsed.stacks);
+                continue;
+            }
 
-            inferno::flamegraph::from_reader(
-                &mut flamegraph_options,
-                &mut collapsed,
-                flamegraph_writer,
-            )
-            .with_context(|| {
-                format!(
-                    "unable to generate a flamegraph file ({}) from the collapsed stack data",
-                    flamegraph_file_path.display()
-                )
-            })?;
+            if let Some(path) = &save_folded {
+                save(&path_for(path, name), &collapsed.stacks)?;
+            }
+
+            outputs.extend(writer.write_all(
+                &recording,
+                &collapsed,
+                &path_for(&output, name),
+                name,
+                format!("{} ({})", binary.path.display(), binary.profile_summary()),
+            )?);
+        }
+
+        if merge {
+            if let Some(path) = &save_folded {
+                save(path, &merged)?;
+            }
+
+            // Names of binaries are root frames, followed by thread names.
+            let roots = 1 + per_thread as usize;
+            writer
+                .write(None, None, &merged, roots, &output, &names.join(", "))
+                .context("failed to write merged profile")?;
+        } else if !is_single || per_thread {
+            write_index(&output, &outputs)?;
         }
 
         Ok(())
     }
 }
+
+/// Options used to write profiles of recordings.
+struct Writer {
+    format: OutputFormat,
+    clock: Clock,
+    per_thread: bool,
+    stacks: StackOptions,
+    render: RenderOptions,
+    timeline: TimelineOptions,
+}
+
+/// Collapsed stacks of a recording.
+struct Collapsed {
+    /// If `--per-thread` is used, thread names are the root frames.
+    stacks: Vec<u8>,
+    /// Stacks of each thread name, if `--per-thread` is used.
+    threads: Option<Vec<ThreadStacks>>,
+}
+
+impl Writer {
+    fn collapse(&self, recording: &Recording) -> Result<Collapsed, Error> {
+        if !self.per_thread {
+            let stacks = match self.clock {
+                Clock::OnCpu => recording.to_collapsed(),
+                _ => recording.to_collapsed_by_time(self.clock, false),
+            }
+            .context("failed to collapse stacks")?;
+            return Ok(Collapsed {
+                stacks,
+                threads: None,
+            });
+        }
+
+        let threads = match self.clock {
+            Clock::OnCpu => recording.to_collapsed_per_thread(),
+            _ => recording
+                .to_collapsed_by_time(self.clock, true)
+                .map(|c| split_threads(&c)),
+        }
+        .context("failed to collapse stacks")?;
+        Ok(Collapsed {
+            stacks: merge_threads(&threads),
+            threads: Some(threads),
+        })
+    }
+
+    /// Writes a profile of `collapsed`, whose first `roots` frames are names
+    /// of merged binaries or threads.
+    ///
+    /// `recording` is used to read samples with timestamps, if it's available.
+    fn write(
+        &self,
+        recording: Option<&Recording>,
+        thread: Option<&str>,
+        collapsed: &[u8],
+        roots: usize,
+        output: &Path,
+        name: &str,
+    ) -> Result<(), Error> {
+        let Writer {
+            render, timeline, ..
+        } = self;
+        let stacks = &self.stacks.with_roots(roots);
+
+        match self.format {
+            OutputFormat::Svg if timeline.is_enabled() => {
+                timeline.render(render, recording, thread, stacks, output)
+            }
+            OutputFormat::Svg => match self.clock {
+                Clock::OnCpu => render.render(&stacks.apply(collapsed), output),
+                Clock::OffCpu => {
+                    render.render_time(&stacks.apply(collapsed), output, "Off-CPU Time Flame Graph")
+                }
+                Clock::Wall => render.render_time(
+                    &stacks.apply(collapsed),
+                    output,
+                    "Wall-Clock Time Flame Graph",
+                ),
+            }
+            .context("failed to render flamegraph"),
+            OutputFormat::Export(format) => {
+                let profile = Profile::new(format, name.to_string(), collapsed, recording, stacks)?;
+                format.write(&profile, output)
+            }
+        }
+    }
+
+    /// Writes a profile of `recording`, or a profile per thread name, and
+    /// returns entries of the index.
+    fn write_all(
+        &self,
+        recording: &Recording,
+        collapsed: &Collapsed,
+        output: &Path,
+        name: &str,
+        detail: String,
+    ) -> Result<Vec<IndexEntry>, Error> {
+        let threads = match &collapsed.threads {
+            Some(threads) => threads,
+            None => {
+                self.write(Some(recording), None, &collapsed.stacks, 0, output, name)?;
+                return Ok(vec![IndexEntry {
+                    name: name.to_string(),
+                    detail,
+                    output: output.to_path_buf(),
+                }]);
+            }
+        };
+
+        let total = threads.iter().map(|t| t.samples).sum::<usize>();
+        let mut outputs = vec![];
+        for thread in threads {
+            let output = with_suffix(output, &file_name_of(&thread.name));
+            self.write(
+                Some(recording),
+                Some(&thread.name),
+                &thread.collapsed,
+                0,
+                &output,
+                &format!("{} ({})", name, thread.name),
+            )
+            .with_context(|| format!("failed to write profile of thread {}", thread.name))?;
+
+            outputs.push(IndexEntry {
+                name: format!("{} / {}", name, thread.name),
+                detail: format!(
+                    "{} thread{}, {:.1}% of samples",
+                    thread.tids.len(),
+                    if thread.tids.len() == 1 { "" } else { "s" },
+                    thread.samples as f64 / total as f64 * 100.0
+                ),
+                output,
+            });
+        }
+
+        Ok(outputs)
+    }
+}
+
+/// An entry of the html file listing flamegraphs.
+struct IndexEntry {
+    name: String,
+    detail: String,
+    output: PathBuf,
+}
+
+fn save(path: &Path, collapsed: &[u8]) -> Result<(), Error> {
+    fs::write(path, collapsed)
+        .with_context(|| format!("failed to save collapsed stacks to {}", path.display()))
+}
+
+/// Returns names of binaries, which can be used in file names.
+///
+/// Test binaries of a library and a binary share the target name, so an
+/// index is appended to duplicates.
+fn unique_names(binaries: &[BinFile]) -> Vec<String> {
+    let mut seen = HashMap::<_, usize>::new();
+
+    binaries
+        .iter()
+        .map(|binary| {
+            let count = seen.entry(&binary.name).or_default();
+            *count += 1;
+            if *count == 1 {
+                binary.name.clone()
+            } else {
+                format!("{}-{}", binary.name, count)
+            }
+        })
+        .collect()
+}
+
+/// `flamegraph.svg` => `flamegraph-{name}.svg`
+fn with_suffix(path: &Path, name: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let file_name = match path.extension() {
+        Some(ext) => format!("{}-{}.{}", stem, name, ext.to_string_lossy()),
+        None => format!("{}-{}", stem, name),
+    };
+
+    path.with_file_name(file_name)
+}
+
+/// Merges stacks of threads, with thread names as root frames.
+fn merge_threads(threads: &[ThreadStacks]) -> Vec<u8> {
+    let mut stacks = vec![];
+    for thread in threads {
+        prepend_frame(&mut stacks, &thread.name, &thread.collapsed);
+    }
+    stacks
+}
+
+/// Appends stacks of `collapsed` to `buf`, with `frame` as the root frame.
+fn prepend_frame(buf: &mut Vec<u8>, frame: &str, collapsed: &[u8]) {
+    for line in collapsed.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            continue;
+        }
+        buf.extend_from_slice(frame.as_bytes());
+        buf.push(b';');
+        buf.extend_from_slice(line);
+        buf.push(b'\n');
+    }
+}
+
+/// Writes an html file linking flamegraphs, next to `output`.
+fn write_index(output: &Path, entries: &[IndexEntry]) -> Result<(), Error> {
+    let path = output.with_extension("html");
+    write_index_to(&path, entries).context("failed to write index of flamegraphs")?;
+    eprintln!("Wrote index of flamegraphs to {}", path.display());
+    Ok(())
+}
+
+fn write_index_to(path: &Path, entries: &[IndexEntry]) -> Result<(), Error> {
+    let mut w = open_output(path)?;
+
+    writeln!(w, "<!DOCTYPE html>")?;
+    writeln!(w, "<html>")?;
+    writeln!(
+        w,
+        "<head><meta charset=\"utf-8\"><title>Flamegraphs</title></head>"
+    )?;
+    writeln!(w, "<body>")?;
+    writeln!(w, "<ul>")?;
+    for entry in entries {
+        let href = entry
+            .output
+            .file_name()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        writeln!(
+            w,
+            "<li><a href=\"{}\">{}</a> <code>{}</code></li>",
+            escape_html(&href),
+            escape_html(&entry.name),
+            escape_html(&entry.detail),
+        )?;
+    }
+    writeln!(w, "</ul>")?;
+    writeln!(w, "</body>")?;
+    writeln!(w, "</html>")?;
+
+    w.flush()?;
+
+    Ok(())
+}
+
+/// Replaces characters which may not be used in file names.
+fn file_name_of(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn focus_keeps_threads_of_per_thread() {
+        let threads = split_threads(
+            b"main-1/1;main;parse;lex 1
+worker-1/2;run;parse 2
+worker-1/3;run;check 3
+",
+        );
+        let stacks = StackOptions::from_iter(&["stacks", "--focus", "^parse$"]);
+
+        assert_eq!(
+            String::from_utf8(stacks.with_roots(1).apply(&merge_threads(&threads))).unwrap(),
+            "main;parse;lex 1\nworker;parse 2\n"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use crate::util::open_output;
+use anyhow::Context;
+use anyhow::Error;
+use inferno::flamegraph::color::PaletteMap;
+use inferno::flamegraph::Direction;
+use inferno::flamegraph::Options;
+use inferno::flamegraph::Palette;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use structopt::StructOpt;
+
+// Options used to render collapsed stacks as a flamegraph.
+//
+// This and other options flattened into commands are not documented with doc
+// comments, because structopt uses them as the description of the commands.
+#[derive(Debug, Clone, StructOpt)]
+pub struct RenderOptions {
+    /// Path to the output file. Use `-` to write to stdout.
+    ///
+    /// Defaults to `flamegraph.svg`, or a file named after the format if
+    /// `--format` is used.
+    #[structopt(short = "o", long, parse(from_os_str), value_name = "PATH")]
+    pub output: Option<PathBuf>,
+
+    /// Title of the flamegraph.
+    #[structopt(long)]
+    title: Option<String>,
+
+    /// Subtitle of the flamegraph.
+    #[structopt(long)]
+    subtitle: Option<String>,
+
+    /// Color palette. e.g. `hot`, `mem`, `io`, `rust`
+    #[structopt(long, default_value = "hot")]
+    palette: Palette,
+
+    /// Load function colors from this file and store new ones into it, so
+    /// colors stay the same between runs.
+    #[structopt(long, parse(from_os_str), value_name = "PATH")]
+    palette_map: Option<PathBuf>,
+
+    /// Plot an icicle graph, which grows from the top to the bottom.
+    #[structopt(long)]
+    inverted: bool,
+
+    /// Reverse the order of stacks, so callees are drawn at the bottom.
+    #[structopt(long)]
+    reverse: bool,
+
+    /// Omit functions narrower than this width, in pixels.
+    #[structopt(long, value_name = "PIXELS", default_value = "0.1")]
+    min_width: f64,
+}
+
+impl RenderOptions {
+    /// Returns `--output`, or `default` if it's not specified.
+    pub(super) fn output_or(&self, default: &str) -> PathBuf {
+        self.output.clone().unwrap_or_else(|| default.into())
+    }
+
+    /// Creates options for inferno.
+    fn to_inferno<'a>(&self, palette_map: Option<&'a mut PaletteMap>) -> Options<'a> {
+        let mut opts = Options::default();
+
+        if let Some(title) = &self.title {
+            opts.title = title.clone();
+        }
+        opts.subtitle = self.subtitle.clone();
+        opts.colors = self.palette;
+        opts.palette_map = palette_map;
+        if self.inverted {
+            opts.direction = Direction::Inverted;
+        }
+        opts.reverse_stack_order = self.reverse;
+        opts.min_width = self.min_width;
+
+        opts
+    }
+
+    /// Renders `collapsed` to `output`, which may be `-` for stdout.
+    pub(super) fn render(&self, collapsed: &[u8], output: &Path) -> Result<(), Error> {
+        self.render_with(output, |opts, writer| {
+            inferno::flamegraph::from_reader(opts, collapsed, writer)
+        })
+    }
+
+    /// Renders `collapsed` weighted by microseconds, instead of sample counts.
+    ///
+    /// `title` is used if `--title` is not specified.
+    pub(super) fn render_time(
+        &self,
+        collapsed: &[u8],
+        output: &Path,
+        title: &str,
+    ) -> Result<(), Error> {
+        self.render_with(output, |opts, writer| {
+            opts.count_name = "us".to_string();
+            if self.title.is_none() {
+                opts.title = title.to_string();
+            }
+            inferno::flamegraph::from_reader(opts, collapsed, writer)
+        })
+    }
+
+    /// Opens the output and the palette map, and invokes `op` with them.
+    pub(super) fn render_with<F, E>(&self, output: &Path, op: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut Options, &mut dyn Write) -> Result<(), E>,
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let mut palette_map = match &self.palette_map {
+            Some(path) => {
+                Some(PaletteMap::load_from_file_or_empty(path).with_context(|| {
+                    format!("failed to load palette map from {}", path.display())
+                })?)
+            }
+            None => None,
+        };
+
+        let mut opts = self.to_inferno(palette_map.as_mut());
+
+        let mut writer = open_output(output)?;
+        op(&mut opts, &mut writer).with_context(|| {
+            format!(
+                "unable to generate a flamegraph file ({}) from the collapsed stack data",
+                output.display()
+            )
+        })?;
+        writer.flush().context("failed to flush the flamegraph")?;
+
+        if let (Some(path), Some(palette_map)) = (&self.palette_map, &palette_map) {
+            palette_map
+                .save_to_file(path)
+                .with_context(|| format!("failed to save palette map to {}", path.display()))?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,420 @@
+//! Flame charts and time ranges, which use timestamps of samples.
+
+use crate::cli_tools::recording::Recording;
+use crate::export::group_by_thread;
+use crate::export::sampling_interval;
+use crate::flamegraph::render::RenderOptions;
+use crate::stacks::to_collapsed;
+use crate::stacks::Sample;
+use crate::stacks::StackOptions;
+use crate::util::parse_duration;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Error;
+use inferno::flamegraph::defaults;
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use structopt::StructOpt;
+
+/// Maximum number of bars in the cpu usage strip.
+const MAX_BUCKETS: usize = 200;
+/// Height of the cpu usage strip, including labels.
+const STRIP_HEIGHT: usize = 80;
+const BAR_TOP: f64 = 18.0;
+const BAR_HEIGHT: f64 = 44.0;
+
+// Options which use timestamps of samples. Only perf records them.
+#[derive(Debug, Clone, StructOpt)]
+pub struct TimelineOptions {
+    /// Create a flame chart, which keeps samples in the order they are taken
+    /// instead of merging identical stacks. Requires `perf.data`.
+    #[structopt(long)]
+    flame_chart: bool,
+
+    /// Render samples taken after this time since the start of the run. e.g.
+    /// `1.5s`, `500ms`, or `25%` of the run. Requires `perf.data`.
+    #[structopt(long, value_name = "TIME")]
+    from_time: Option<TimeBound>,
+
+    /// Render samples taken before this time since the start of the run. e.g.
+    /// `3s`, or `75%` of the run. Requires `perf.data`.
+    #[structopt(long, value_name = "TIME")]
+    to_time: Option<TimeBound>,
+
+    /// Draw the cpu usage over time below the flamegraph, so a time range can
+    /// be chosen. Requires `perf.data`.
+    ///
+    /// It's also drawn if `--flame-chart`, `--from-time` or `--to-time` is
+    /// used.
+    #[structopt(long)]
+    cpu_usage: bool,
+}
+
+/// A point of time relative to the first sample.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimeBound {
+    Seconds(f64),
+    /// `0` to `100`.
+    Percent(f64),
+}
+
+impl TimeBound {
+    /// Returns seconds since the start of a run which lasts `duration`
+    /// seconds.
+    fn resolve(self, duration: f64) -> f64 {
+        match self {
+            TimeBound::Seconds(secs) => secs,
+            TimeBound::Percent(percent) => duration * percent / 100.0,
+        }
+    }
+}
+
+impl FromStr for TimeBound {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().strip_suffix('%') {
+            Some(percent) => {
+                let percent = percent
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid percentage: {}", s))?;
+                if !(0.0..=100.0).contains(&percent) {
+                    return Err(format!("percentage should be between 0 and 100: {}", s));
+                }
+                Ok(TimeBound::Percent(percent))
+            }
+            None => parse_duration(s).map(|d| TimeBound::Seconds(d.as_secs_f64())),
+        }
+    }
+}
+
+impl TimelineOptions {
+    /// Returns true if samples with timestamps are required.
+    pub(super) fn is_enabled(&self) -> bool {
+        self.flame_chart || self.from_time.is_some() || self.to_time.is_some() || self.cpu_usage
+    }
+
+    /// Renders samples of `recording` taken in the time range, with the cpu
+    /// usage strip at the bottom.
+    ///
+    /// If `thread` is specified, only samples of threads with the name are
+    /// used.
+    pub(super) fn render(
+        &self,
+        render: &RenderOptions,
+        recording: Option<&Recording>,
+        thread: Option<&str>,
+        stacks: &StackOptions,
+        output: &Path,
+    ) -> Result<(), Error> {
+        let samples = match recording.map(|r| r.samples(false)).transpose()? {
+            Some(Some(samples)) => samples,
+            _ => bail!(
+                "`--flame-chart`, `--from-time`, `--to-time` and `--cpu-usage` require samples \
+                 with timestamps, which are recorded only by perf"
+            ),
+        };
+        let mut samples = stacks.apply_samples(samples);
+        if let Some(thread) = thread {
+            // perf replaces spaces while collapsing stacks.
+            samples.retain(|s| s.comm.replace(' ', "_") == thread);
+        }
+        samples.sort_by(|a, b| a.time.total_cmp(&b.time));
+        if samples.is_empty() {
+            bail!("no samples were recorded");
+        }
+
+        let interval = sampling_interval(&group_by_thread(&samples));
+        let start = samples[0].time;
+        let end = samples[samples.len() - 1].time + interval;
+        let duration = end - start;
+
+        let from = start + self.from_time.map_or(0.0, |b| b.resolve(duration));
+        let to = start + self.to_time.map_or(duration, |b| b.resolve(duration));
+        if from >= to {
+            bail!(
+                "the time range is empty: {:.3}s to {:.3}s of {:.3}s",
+                from - start,
+                to - start,
+                duration
+            );
+        }
+
+        let selected = samples
+            .iter()
+            .filter(|s| from <= s.time && s.time < to)
+            .collect::<Vec<_>>();
+        if selected.is_empty() {
+            bail!(
+                "no samples were taken between {:.3}s and {:.3}s",
+                from - start,
+                to - start
+            );
+        }
+
+        let collapsed = if self.flame_chart {
+            flame_chart(&selected)
+        } else {
+            to_collapsed(selected.iter().map(|s| (stack_of(s), 1)))
+        };
+
+        let usage = cpu_usage(&samples, start, end, interval);
+        let flame_chart = self.flame_chart;
+
+        render
+            .render_with(output, |opts, w| {
+                if flame_chart {
+                    opts.flame_chart = true;
+                    if opts.title == defaults::TITLE {
+                        opts.title = defaults::CHART_TITLE.to_string();
+                    }
+                }
+
+                let mut svg = vec![];
+                inferno::flamegraph::from_reader(opts, &*collapsed, &mut svg)
+                    .map_err(io::Error::other)?;
+                let svg = String::from_utf8(svg).map_err(io::Error::other)?;
+
+                let svg = append_strip(&svg, &usage, duration, (from - start, to - start))
+                    .ok_or_else(|| io::Error::other("unexpected svg header from inferno"))?;
+                w.write_all(svg.as_bytes())
+            })
+            .context("failed to render flamegraph")
+    }
+}
+
+fn stack_of(sample: &Sample) -> String {
+    sample
+        .stack
+        .iter()
+        .map(|f| &*f.name)
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Creates collapsed lines for a flame chart, merging consecutive samples of
+/// identical stacks.
+///
+/// Lines are written from the last sample, because inferno reverses them in
+/// flame chart mode.
+fn flame_chart(samples: &[&Sample]) -> Vec<u8> {
+    let mut lines = Vec::<(String, usize)>::new();
+    for sample in samples {
+        if sample.stack.is_empty() {
+            continue;
+        }
+        let stack = stack_of(sample);
+        match lines.last_mut() {
+            Some((last, count)) if *last == stack => *count += 1,
+            _ => lines.push((stack, 1)),
+        }
+    }
+
+    let mut buf = vec![];
+    for (stack, count) in lines.iter().rev() {
+        buf.extend_from_slice(stack.as_bytes());
+        buf.push(b' ');
+        buf.extend_from_slice(count.to_string().as_bytes());
+        buf.push(b'\n');
+    }
+    buf
+}
+
+/// Splits the run into time ranges which are not shorter than `interval`,
+/// and returns the average number of busy cpus for each of them.
+///
+/// Each sample is assumed to account for `interval` seconds.
+fn cpu_usage(samples: &[Sample], start: f64, end: f64, interval: f64) -> Vec<f64> {
+    let buckets = ((end - start) / interval)
+        .floor()
+        .max(1.0)
+        .min(MAX_BUCKETS as f64) as usize;
+    let bucket_len = (end - start) / buckets as f64;
+
+    let mut usage = vec![0.0; buckets];
+    for sample in samples {
+        let idx = ((sample.time - start) / bucket_len) as usize;
+        usage[idx.min(buckets - 1)] += interval / bucket_len;
+    }
+    usage
+}
+
+/// Makes the svg taller, and draws `usage` in the added space along with the
+/// selected range.
+///
+/// Coordinates are percentages, because inferno makes the width of the svg
+/// fluid.
+///
+/// Returns `None` if the header of `svg` is not the one written by inferno.
+fn append_strip(svg: &str, usage: &[f64], duration: f64, range: (f64, f64)) -> Option<String> {
+    let header_start = svg.find("<svg ")?;
+    let header_end = header_start + svg[header_start..].find('>')?;
+    let header = &svg[header_start..header_end];
+
+    let height_start = header.find(" height=\"")? + " height=\"".len();
+    let height_len = header[height_start..].find('"')?;
+    let height = header[height_start..height_start + height_len]
+        .parse::<usize>()
+        .ok()?;
+    let new_height = height + STRIP_HEIGHT;
+
+    let header = header
+        .replacen(
+            &format!(" height=\"{}\"", height),
+            &format!(" height=\"{}\"", new_height),
+            1,
+        )
+        .replacen(&format!(" {}\"", height), &format!(" {}\"", new_height), 1);
+
+    let x = |secs: f64| 1.0 + 98.0 * (secs / duration).clamp(0.0, 1.0);
+    let y = height as f64;
+    let max = usage.iter().copied().fold(1.0, f64::max);
+    let bucket_len = duration / usage.len() as f64;
+
+    let mut strip = String::new();
+    let _ = writeln!(strip, "<g id=\"cpu-usage\">");
+    let _ = writeln!(
+        strip,
+        "<rect x=\"0\" y=\"{}\" width=\"100%\" height=\"{}\" fill=\"#eeeeee\"/>",
+        y, STRIP_HEIGHT
+    );
+    let _ = writeln!(
+        strip,
+        "<text x=\"1%\" y=\"{}\" font-size=\"12\" font-family=\"Verdana\">CPU usage (max {:.2} \
+         CPUs)</text>",
+        y + 14.0,
+        max
+    );
+    for (i, &cpus) in usage.iter().enumerate() {
+        if cpus == 0.0 {
+            continue;
+        }
+        let bar = BAR_HEIGHT * cpus / max;
+        let _ = writeln!(
+            strip,
+            "<rect x=\"{:.3}%\" y=\"{:.1}\" width=\"{:.3}%\" height=\"{:.1}\" \
+             fill=\"rgb(230,120,40)\"><title>{}: {:.2} CPUs</title></rect>",
+            x(i as f64 * bucket_len),
+            y + BAR_TOP + BAR_HEIGHT - bar,
+            98.0 / usage.len() as f64,
+            bar,
+            format_secs(i as f64 * bucket_len),
+            cpus
+        );
+    }
+
+    // Dim the time outside of the range.
+    for &(from, to) in &[(0.0, range.0), (range.1, duration)] {
+        if from < to {
+            let _ = writeln!(
+                strip,
+                "<rect x=\"{:.3}%\" y=\"{}\" width=\"{:.3}%\" height=\"{}\" fill=\"black\" \
+                 fill-opacity=\"0.3\"/>",
+                x(from),
+                y + BAR_TOP,
+                x(to) - x(from),
+                BAR_HEIGHT
+            );
+        }
+    }
+
+    for i in 0..=4 {
+        let secs = duration * i as f64 / 4.0;
+        let anchor = match i {
+            0 => "start",
+            4 => "end",
+            _ => "middle",
+        };
+        let _ = writeln!(
+            strip,
+            "<text x=\"{:.3}%\" y=\"{}\" font-size=\"11\" font-family=\"Verdana\" \
+             text-anchor=\"{}\">{}</text>",
+            x(secs),
+            y + BAR_TOP + BAR_HEIGHT + 13.0,
+            anchor,
+            format_secs(secs)
+        );
+    }
+    let _ = writeln!(strip, "</g>");
+
+    let end = svg.rfind("</svg>")?;
+    Some(format!(
+        "{}{}{}{}{}",
+        &svg[..header_start],
+        header,
+        &svg[header_end..end],
+        strip,
+        &svg[end..]
+    ))
+}
+
+fn format_secs(secs: f64) -> String {
+    if secs != 0.0 && secs < 0.1 {
+        format!("{:.3}ms", secs * 1000.0)
+    } else {
+        format!("{:.3}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sample(time: f64, stack: &[&str]) -> Sample {
+        Sample::new(1, time, stack)
+    }
+
+    #[test]
+    fn time_bounds_can_be_parsed() {
+        assert_eq!("1.5s".parse(), Ok(TimeBound::Seconds(1.5)));
+        assert_eq!("500ms".parse(), Ok(TimeBound::Seconds(0.5)));
+        assert_eq!("25%".parse(), Ok(TimeBound::Percent(25.0)));
+        assert!("150%".parse::<TimeBound>().is_err());
+    }
+
+    #[test]
+    fn flame_chart_keeps_order() {
+        let samples = [
+            sample(1.0, &["main", "parse"]),
+            sample(2.0, &["main", "parse"]),
+            sample(3.0, &["main", "check"]),
+            sample(4.0, &["main", "parse"]),
+        ];
+        let collapsed = flame_chart(&samples.iter().collect::<Vec<_>>());
+
+        assert_eq!(
+            String::from_utf8(collapsed).unwrap(),
+            "main;parse 1\nmain;check 1\nmain;parse 2\n"
+        );
+    }
+
+    #[test]
+    fn cpu_usage_per_time_range() {
+        let samples = [
+            sample(1.0, &["main"]),
+            sample(1.25, &["main"]),
+            sample(1.25, &["main"]),
+            sample(1.75, &["main"]),
+        ];
+        let usage = cpu_usage(&samples, 1.0, 2.0, 0.25);
+
+        assert_eq!(usage.len(), 4);
+        assert!((usage[0] - 1.0).abs() < 1e-9);
+        assert!((usage[1] - 2.0).abs() < 1e-9);
+        assert_eq!(usage[2], 0.0);
+    }
+
+    #[test]
+    fn strip_is_appended() {
+        let svg = "<?xml?><svg version=\"1.1\" width=\"1200\" height=\"100\" \
+                   viewBox=\"0 0 1200 100\"><g id=\"frames\"></g></svg>";
+        let svg = append_strip(svg, &[1.0, 2.0], 2.0, (0.5, 2.0)).unwrap();
+
+        assert!(svg.contains(&format!(" height=\"{}\"", 100 + STRIP_HEIGHT)));
+        assert!(svg.contains(&format!("viewBox=\"0 0 1200 {}\"", 100 + STRIP_HEIGHT)));
+        assert!(svg.contains("<g id=\"frames\"></g><g id=\"cpu-usage\">"));
+        assert!(svg.ends_with("</g>\n</svg>"));
+    }
+}
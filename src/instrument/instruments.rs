@@ -4,6 +4,7 @@
 
 use super::InstrumentsCommand;
 use crate::cargo::cargo_workspace;
+use crate::cargo::BinFile;
 use anyhow::{anyhow, Result};
 use semver::Version;
 use std::fs;
@@ -85,32 +86,32 @@ impl XcodeInstruments {
         match self {
             XcodeInstruments::XcTrace => {
                 let mut command = Command::new("xcrun");
-                command.args(&["xctrace", "record"]);
+                command.args(["xctrace", "record"]);
 
-                command.args(&["--template", template_name]);
+                command.args(["--template", template_name]);
 
                 if let Some(limit_millis) = time_limit {
                     let limit_millis_str = format!("{}ms", limit_millis);
-                    command.args(&["--time-limit", &limit_millis_str]);
+                    command.args(["--time-limit", &limit_millis_str]);
                 }
 
-                command.args(&["--output", trace_filepath.to_str().unwrap()]);
+                command.args(["--output", trace_filepath.to_str().unwrap()]);
                 // redirect stdin & err to the user's terminal
                 if let Some(tty) = get_tty()? {
-                    command.args(&["--target-stdin", &tty, "--target-stdout", &tty]);
+                    command.args(["--target-stdin", &tty, "--target-stdout", &tty]);
                 }
 
-                command.args(&["--launch", "--"]);
+                command.args(["--launch", "--"]);
                 Ok(command)
             }
             XcodeInstruments::InstrumentsBinary => {
                 let mut command = Command::new("instruments");
-                command.args(&["-t", template_name]);
+                command.args(["-t", template_name]);
 
-                command.arg("-D").arg(&trace_filepath);
+                command.arg("-D").arg(trace_filepath);
 
                 if let Some(limit) = time_limit {
-                    command.args(&["-l", &limit.to_string()]);
+                    command.args(["-l", &limit.to_string()]);
                 }
                 Ok(command)
             }
@@ -123,9 +124,8 @@ impl XcodeInstruments {
 /// This function parses the output of `sw_vers -productVersion` (a string like '11.2.3`)
 /// and returns the corresponding semver struct `Version{major: 11, minor: 2, patch: 3}`.
 fn get_macos_version() -> Result<Version> {
-    let Output { status, stdout, .. } = Command::new("sw_vers")
-        .args(&["-productVersion"])
-        .output()?;
+    let Output { status, stdout, .. } =
+        Command::new("sw_vers").args(["-productVersion"]).output()?;
 
     if !status.success() {
         return Err(anyhow!("macOS version cannot be determined"));
@@ -193,7 +193,7 @@ fn parse_xctrace_template_list() -> Result<TemplateCatalog> {
         stdout,
         stderr,
     } = Command::new("xcrun")
-        .args(&["xctrace", "list", "templates"])
+        .args(["xctrace", "list", "templates"])
         .output()?;
 
     if !status.success() {
@@ -264,7 +264,7 @@ fn parse_xctrace_template_list() -> Result<TemplateCatalog> {
 /// ```
 fn parse_instruments_template_list() -> Result<TemplateCatalog> {
     let Output { status, stdout, .. } = Command::new("instruments")
-        .args(&["-s", "templates"])
+        .args(["-s", "templates"])
         .output()?;
 
     if !status.success() {
@@ -445,10 +445,12 @@ fn abbrev_name(template_name: &str) -> Option<&str> {
 /// Profile the target binary at `binary_filepath`, write results at
 /// `trace_filepath` and returns its path.
 pub(crate) fn profile_target(
-    target_filepath: &Path,
+    target: &BinFile,
     xctrace_tool: &XcodeInstruments,
     cmd: &InstrumentsCommand,
 ) -> Result<PathBuf> {
+    let target_filepath = target.path.as_path();
+
     // 1. Get the template name from config
     // This borrows a ref to the String in Option<String>. The value can be
     // unwrapped because in this version the template was checked earlier to
@@ -456,7 +458,7 @@ pub(crate) fn profile_target(
     let template_name = resolve_template_name(cmd.template_name.as_deref().unwrap());
 
     // 2. Compute the trace filepath and create its parent directory
-    let workspace_root = cargo_workspace()?;
+    let workspace_root = cargo_workspace(&cmd.target)?;
     let trace_filepath =
         prepare_trace_filepath(target_filepath, template_name, workspace_root.as_path())?;
 
@@ -466,7 +468,12 @@ pub(crate) fn profile_target(
             .strip_prefix(workspace_root)
             .unwrap_or(target_filepath)
             .to_string_lossy();
-        let status_detail = format!("{} with template '{}'", target_shortpath, template_name);
+        let status_detail = format!(
+            "{} ({}) with template '{}'",
+            target_shortpath,
+            target.profile_summary(),
+            template_name
+        );
 
         eprintln!("Profiling {}", status_detail);
     }
@@ -474,7 +481,7 @@ pub(crate) fn profile_target(
     let mut command =
         xctrace_tool.profiling_command(template_name, &trace_filepath, cmd.time_limit)?;
 
-    command.arg(&target_filepath);
+    command.arg(target_filepath);
 
     if !cmd.target.args().is_empty() {
         command.args(cmd.target.args());
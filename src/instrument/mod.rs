@@ -55,7 +55,7 @@ impl InstrumentsCommand {
         }
 
         // 3. Build the specified target
-        let workspace = cargo_workspace()?;
+        let workspace = cargo_workspace(&self.target)?;
         let binaries = compile(&self.target).context("failed to compile")?;
 
         if binaries.len() != 1 {
@@ -80,14 +80,14 @@ impl InstrumentsCommand {
         }
 
         // 4. Profile the built target, will display menu if no template was selected
-        let trace_filepath = profile_target(&target_filepath.path, &xctrace_tool, &self)
+        let trace_filepath = profile_target(&target_filepath, &xctrace_tool, &self)
             .context("failed to profile built binary")?;
 
         // 5. Print the trace file's relative path
         {
             let trace_shortpath = trace_filepath
                 .strip_prefix(&workspace)
-                .unwrap_or_else(|_| trace_filepath.as_path())
+                .unwrap_or(trace_filepath.as_path())
                 .to_string_lossy();
 
             eprintln!("Trace file {}", trace_shortpath);
@@ -134,7 +134,7 @@ fn codesign(path: &Path) -> Result<(), Error> {
     std::fs::write(&entitlement_path, ENTITLEMENTS_PLIST_DATA.as_bytes())?;
 
     let output = Command::new("codesign")
-        .args(&["-s", "-", "-f", "--entitlements"])
+        .args(["-s", "-", "-f", "--entitlements"])
         .args([&entitlement_path, path])
         .output()?;
     if !output.status.success() {
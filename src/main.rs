@@ -1,6 +1,7 @@
 use crate::cargo::compile;
 use crate::cargo::CargoTarget;
 use crate::cpu::CpuCommand;
+use crate::export::ExportCommand;
 use crate::flamegraph::FlameGraphCommand;
 use crate::instrument::InstrumentsCommand;
 use crate::trace::TraceCommand;
@@ -8,13 +9,18 @@ use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
 use std::env;
+use structopt::clap::App;
 use structopt::StructOpt;
 
 mod cargo;
 mod cli_tools;
+mod config;
 mod cpu;
+mod export;
 mod flamegraph;
 mod instrument;
+mod stacks;
+mod symbolize;
 mod trace;
 mod util;
 
@@ -24,12 +30,14 @@ mod util;
     author,
     about = "The performance profiler for cargo"
 )]
+#[allow(clippy::large_enum_variant)]
 pub enum SubCommand {
     /// WIP. Run all benchmark and store result as a json file.
     All,
     Flamegraph(FlameGraphCommand),
     Trace(TraceCommand),
     Cpu(CpuCommand),
+    Export(ExportCommand),
 
     Instruments(InstrumentsCommand),
 
@@ -43,6 +51,11 @@ pub enum SubCommand {
     },
 }
 
+/// Parser of the command line, which accepts `--preset` for every command.
+fn app() -> App<'static, 'static> {
+    SubCommand::clap().arg(config::preset_arg())
+}
+
 fn main() -> Result<(), Error> {
     let mut args = env::args_os().collect::<Vec<_>>();
 
@@ -50,16 +63,14 @@ fn main() -> Result<(), Error> {
         if args.first().unwrap() == "cargo" {
             args.remove(1);
         } else {
-            if match args.get(1) {
-                Some(arg) if arg == "profile" => true,
-                _ => false,
-            } {
+            if matches!(args.get(1), Some(arg) if arg == "profile") {
                 args.remove(1);
             }
         }
     }
 
-    let cmd: SubCommand = SubCommand::from_iter(args);
+    let args = config::apply(args, app).context("failed to read config")?;
+    let cmd = SubCommand::from_clap(&app().get_matches_from(args));
 
     match cmd {
         SubCommand::All => {}
@@ -75,11 +86,14 @@ fn main() -> Result<(), Error> {
                      bin-path`"
                 )
             }
+            // stdout is used by shells.
+            eprintln!("Built with {}", binraries[0].profile_summary());
             print!("{}", binraries[0].path.display());
         }
 
         SubCommand::Trace(trace) => trace.run().context("failed to trace")?,
         SubCommand::Cpu(cmd) => cmd.run().context("failed to profile cpu usage")?,
+        SubCommand::Export(cmd) => cmd.run().context("failed to export profile")?,
         SubCommand::Instruments(cmd) => cmd.run().context("failed to instrument")?,
     }
 
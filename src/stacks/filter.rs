@@ -0,0 +1,121 @@
+use regex::Regex;
+use structopt::StructOpt;
+
+// Options used to select stacks.
+#[derive(Debug, Clone, StructOpt)]
+pub struct FilterOptions {
+    /// Keep only stacks containing a frame matching this regex, and make the
+    /// first matching frame the root. Names of binaries and threads merged by
+    /// `--merge` are kept.
+    #[structopt(long, value_name = "REGEX")]
+    focus: Option<Regex>,
+
+    /// Drop stacks containing a frame matching this regex.
+    #[structopt(long, value_name = "REGEX")]
+    exclude: Option<Regex>,
+
+    /// Remove callees of frames matching this regex. Time spent in callees is
+    /// attributed to the matching frame.
+    #[structopt(long, value_name = "REGEX")]
+    prune_below: Option<Regex>,
+
+    /// Merge frames of recursive calls, so `a;b;b;b;c` becomes `a;b;c`.
+    #[structopt(long)]
+    collapse_recursion: bool,
+}
+
+impl FilterOptions {
+    /// Filters frames of a stack, from the root to the leaf. Returns `None` if
+    /// the stack should be dropped.
+    pub fn filter<S>(&self, mut frames: Vec<S>) -> Option<Vec<S>>
+    where
+        S: AsRef<str>,
+    {
+        if let Some(exclude) = &self.exclude {
+            if frames.iter().any(|frame| exclude.is_match(frame.as_ref())) {
+                return None;
+            }
+        }
+
+        if let Some(focus) = &self.focus {
+            let idx = frames
+                .iter()
+                .position(|frame| focus.is_match(frame.as_ref()))?;
+            frames.drain(..idx);
+        }
+
+        if let Some(prune_below) = &self.prune_below {
+            if let Some(idx) = frames
+                .iter()
+                .position(|frame| prune_below.is_match(frame.as_ref()))
+            {
+                frames.truncate(idx + 1);
+            }
+        }
+
+        if self.collapse_recursion {
+            frames.dedup_by(|a, b| a.as_ref() == b.as_ref());
+        }
+
+        Some(frames)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::StackOptions;
+    use super::*;
+
+    fn apply(args: &[&str], input: &str) -> String {
+        let opts = StackOptions::from_iter(std::iter::once("stacks").chain(args.iter().copied()));
+
+        String::from_utf8(opts.apply(input.as_bytes())).unwrap()
+    }
+
+    #[test]
+    fn focus() {
+        assert_eq!(
+            apply(&["--focus", "^parse"], "main;parse;lex 1\nmain;check 2\n"),
+            "parse;lex 1\n"
+        );
+    }
+
+    #[test]
+    fn focus_keeps_roots() {
+        let opts = StackOptions::from_iter(&["stacks", "--focus", "^parse"]).with_roots(1);
+        assert_eq!(
+            String::from_utf8(opts.apply(b"a;main;parse;lex 1\nb;main;check 2\n")).unwrap(),
+            "a;parse;lex 1\n"
+        );
+    }
+
+    #[test]
+    fn exclude() {
+        assert_eq!(
+            apply(
+                &["--exclude", "^check$"],
+                "main;parse;lex 1\nmain;check;a 2\n"
+            ),
+            "main;parse;lex 1\n"
+        );
+    }
+
+    #[test]
+    fn prune_below() {
+        assert_eq!(
+            apply(
+                &["--prune-below", "^parse$"],
+                "main;parse;lex 1\nmain;parse;expr 2\n"
+            ),
+            "main;parse 3\n"
+        );
+    }
+
+    #[test]
+    fn collapse_recursion() {
+        assert_eq!(
+            apply(&["--collapse-recursion"], "main;expr;expr;expr;lit 1\n"),
+            "main;expr;lit 1\n"
+        );
+    }
+}
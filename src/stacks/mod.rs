@@ -0,0 +1,336 @@
+//! Transforms collapsed stacks before they are rendered or aggregated.
+
+use self::filter::FilterOptions;
+use self::normalize::NormalizeOptions;
+use crate::util::for_each_line;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+mod filter;
+mod normalize;
+pub mod offcpu;
+
+// Options used to transform collapsed stacks.
+#[derive(Debug, Clone, StructOpt)]
+pub struct StackOptions {
+    #[structopt(flatten)]
+    normalize: NormalizeOptions,
+
+    #[structopt(flatten)]
+    filter: FilterOptions,
+
+    /// Number of root frames of collapsed stacks which are kept as is.
+    #[structopt(skip)]
+    roots: usize,
+}
+
+impl StackOptions {
+    /// Returns options which keep `roots` root frames of collapsed stacks,
+    /// like names of threads or binaries merged by `--merge`, so `--focus`
+    /// does not remove them.
+    pub fn with_roots(&self, roots: usize) -> StackOptions {
+        StackOptions {
+            roots,
+            ..self.clone()
+        }
+    }
+
+    /// Normalizes frames, and then filters stacks.
+    ///
+    /// Stacks are transformed one by one, so only the result is kept in
+    /// memory.
+    pub fn apply(&self, collapsed: &[u8]) -> Vec<u8> {
+        let normalizer = self.normalize.normalizer();
+
+        let mut counts = StackCounts::default();
+        for_each_stack(collapsed, |stack, count| {
+            let mut frames = stack.split(';');
+            let roots = frames.by_ref().take(self.roots).collect::<Vec<_>>();
+            let frames = normalizer.normalize(frames);
+            if let Some(frames) = self.filter.filter(frames) {
+                let stack = roots
+                    .into_iter()
+                    .chain(frames.iter().map(|frame| &**frame))
+                    .collect::<Vec<_>>();
+                counts.add(&stack.join(";"), count);
+            }
+        });
+        counts.into_collapsed()
+    }
+
+    /// Same as [StackOptions::apply], but for the stack of each sample.
+    ///
+    /// Samples of stacks removed by filters are dropped.
+    pub fn apply_samples(&self, samples: Vec<Sample>) -> Vec<Sample> {
+        let normalizer = self.normalize.normalizer();
+
+        samples
+            .into_iter()
+            .filter_map(|sample| {
+                let stack = normalizer.normalize_frames(sample.stack);
+                let stack = self.filter.filter(stack)?;
+
+                Some(Sample { stack, ..sample })
+            })
+            .collect()
+    }
+}
+
+/// A stack sampled at a point of time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// Name of the thread.
+    pub comm: String,
+    pub pid: u32,
+    pub tid: u32,
+    /// Timestamp in seconds.
+    pub time: f64,
+    /// Name of the sampled event, like `cycles`.
+    pub event: Option<Arc<str>>,
+    /// Weight of the sample in units of the event, like nanoseconds for
+    /// `cpu-clock`. `1` if unknown.
+    pub period: u64,
+    /// Frames from the root to the leaf.
+    pub stack: Vec<Frame>,
+}
+
+#[cfg(test)]
+impl Sample {
+    /// A sample of thread `tid` of the process `1`, named `main`.
+    pub fn new(tid: u32, time: f64, stack: &[&str]) -> Self {
+        Sample {
+            comm: "main".into(),
+            pid: 1,
+            tid,
+            time,
+            event: None,
+            period: 1,
+            stack: stack.iter().copied().map(Frame::new).collect(),
+        }
+    }
+}
+
+/// A thread switched in or out of a cpu.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContextSwitch {
+    pub pid: u32,
+    pub tid: u32,
+    /// Timestamp in seconds.
+    pub time: f64,
+    /// True if the thread is switched out.
+    pub out: bool,
+    /// True if the thread is switched out while it's runnable.
+    pub preempted: bool,
+}
+
+/// A frame of a sampled stack.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Frame {
+    /// Name of the function.
+    pub name: String,
+    /// Path to the binary or the shared library.
+    pub module: Option<String>,
+    /// Address of the instruction.
+    pub address: Option<u64>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+impl Frame {
+    pub fn new(name: impl Into<String>) -> Self {
+        Frame {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+}
+
+impl AsRef<str> for Frame {
+    fn as_ref(&self) -> &str {
+        &self.name
+    }
+}
+
+/// Splits a collapsed line like `main;foo;bar 10` into the stack and the
+/// sample count.
+pub fn parse_line(line: &str) -> Option<(&str, usize)> {
+    let (stack, count) = line.trim_end().rsplit_once(' ')?;
+    let count = match count.split_once('.') {
+        // Fractional parts are dropped, like inferno does.
+        Some((int, _)) => int.parse().ok()?,
+        None => count.parse().ok()?,
+    };
+
+    Some((stack, count))
+}
+
+/// Calls `f` with the stack and the sample count of each collapsed line,
+/// without copying all of them.
+pub fn for_each_stack(collapsed: &[u8], mut f: impl FnMut(&str, usize)) {
+    for_each_line(collapsed, |line| {
+        if let Some((stack, count)) = parse_line(line) {
+            f(stack, count);
+        }
+    })
+    .expect("reading a slice does not fail");
+}
+
+/// Sums sample counts of identical stacks while they are streamed, so memory
+/// usage depends on the number of distinct stacks, not samples.
+#[derive(Debug, Default)]
+pub struct StackCounts {
+    counts: HashMap<String, usize>,
+    /// Reused by [StackCounts::add_sample].
+    buf: String,
+}
+
+impl StackCounts {
+    pub fn add(&mut self, stack: &str, count: usize) {
+        // Stacks are mostly seen before, so they are not copied for lookups.
+        match self.counts.get_mut(stack) {
+            Some(total) => *total += count,
+            None => {
+                self.counts.insert(stack.to_string(), count);
+            }
+        }
+    }
+
+    /// Counts the stack of `sample`, whose root frame is the name of the
+    /// thread, like stacks collapsed from the output of `perf script`.
+    ///
+    /// If `threads` is true, the root frame is `{name}-{pid}/{tid}` instead.
+    pub fn add_sample(&mut self, sample: &Sample, threads: bool) {
+        let mut stack = std::mem::take(&mut self.buf);
+        stack.clear();
+        stack.push_str(&sample.comm.replace(' ', "_"));
+        if threads {
+            stack.push_str(&format!("-{}/{}", sample.pid, sample.tid));
+        }
+        for frame in &sample.stack {
+            stack.push(';');
+            stack.push_str(&frame.name);
+        }
+
+        self.add(&stack, 1);
+        self.buf = stack;
+    }
+
+    /// Writes stacks as sorted collapsed lines.
+    pub fn into_collapsed(self) -> Vec<u8> {
+        let mut counts = self.counts.into_iter().collect::<Vec<_>>();
+        counts.sort_unstable();
+
+        let mut buf = vec![];
+        for (stack, count) in counts {
+            buf.extend_from_slice(stack.as_bytes());
+            buf.push(b' ');
+            buf.extend_from_slice(count.to_string().as_bytes());
+            buf.push(b'\n');
+        }
+        buf
+    }
+}
+
+/// Sums sample counts of identical stacks, and writes them as sorted
+/// collapsed lines.
+pub fn to_collapsed<I, S>(stacks: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = (S, usize)>,
+    S: AsRef<str>,
+{
+    let mut counts = StackCounts::default();
+    for (stack, count) in stacks {
+        counts.add(stack.as_ref(), count);
+    }
+    counts.into_collapsed()
+}
+
+/// Collapsed stacks of threads with the same name, like workers of a thread
+/// pool.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ThreadStacks {
+    pub name: String,
+    /// Ids of threads with the name.
+    pub tids: BTreeSet<u32>,
+    pub samples: usize,
+    /// Stacks without the frame of the thread.
+    pub collapsed: Vec<u8>,
+}
+
+/// Groups collapsed stacks by the thread name, where the root frame of each
+/// stack is `{name}-{pid}/{tid}`.
+///
+/// Threads are sorted by the number of samples, in descending order.
+pub fn split_threads(collapsed: &[u8]) -> Vec<ThreadStacks> {
+    let mut threads = BTreeMap::<String, (BTreeSet<u32>, usize, StackCounts)>::new();
+    for_each_stack(collapsed, |stack, count| {
+        let (thread, stack) = match stack.split_once(';') {
+            Some(v) => v,
+            None => return,
+        };
+        let (name, tid) = match thread.rsplit_once('-') {
+            Some((name, ids)) => match ids.split_once('/').map(|(_, tid)| tid.parse::<u32>()) {
+                Some(Ok(tid)) => (name, Some(tid)),
+                _ => (thread, None),
+            },
+            None => (thread, None),
+        };
+
+        if !threads.contains_key(name) {
+            threads.insert(name.to_string(), Default::default());
+        }
+        let (tids, samples, stacks) = threads.get_mut(name).unwrap();
+        tids.extend(tid);
+        *samples += count;
+        stacks.add(stack, count);
+    });
+
+    let mut threads = threads
+        .into_iter()
+        .map(|(name, (tids, samples, stacks))| ThreadStacks {
+            name,
+            tids,
+            samples,
+            collapsed: stacks.into_collapsed(),
+        })
+        .collect::<Vec<_>>();
+    threads.sort_by(|a, b| b.samples.cmp(&a.samples).then_with(|| a.name.cmp(&b.name)));
+    threads
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn threads_are_grouped_by_name() {
+        let threads = split_threads(
+            b"main-1/1;main;parse 3
+rayon-worker-1/2;rayon::work;compile 2
+rayon-worker-1/3;rayon::work;compile 4
+rayon-worker-1/3;rayon::work;link 1
+",
+        );
+
+        assert_eq!(
+            threads,
+            vec![
+                ThreadStacks {
+                    name: "rayon-worker".into(),
+                    tids: vec![2, 3].into_iter().collect(),
+                    samples: 7,
+                    collapsed: b"rayon::work;compile 6\nrayon::work;link 1\n".to_vec(),
+                },
+                ThreadStacks {
+                    name: "main".into(),
+                    tids: vec![1].into_iter().collect(),
+                    samples: 3,
+                    collapsed: b"main;parse 3\n".to_vec(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,303 @@
+use super::Frame;
+use regex::Regex;
+use rustc_demangle::try_demangle;
+use std::borrow::Cow;
+use structopt::StructOpt;
+
+/// Frames of the rust runtime and closure shims, which are hidden by default.
+const BOILERPLATE_FRAMES: &[&str] = &[
+    r"^std::rt::lang_start",
+    r"^std::sys_common::backtrace::__rust_begin_short_backtrace",
+    r"^std::sys::backtrace::__rust_begin_short_backtrace",
+    r"^std::panicking::(r#)?try",
+    r"^std::panic::catch_unwind",
+    r"^__rust_try",
+    r"^core::ops::function::FnOnce::call_once",
+    r"^core::ops::function::impls::",
+    r"^<.+ as (core::ops::function::)?Fn(Once|Mut)?<.*>>::call(_once|_mut)?(\{\{vtable\.shim\}\})?$",
+];
+
+// Options used to clean up symbols of rust functions.
+#[derive(Debug, Clone, StructOpt)]
+pub struct NormalizeOptions {
+    /// Keep symbols as is, instead of demangling them and stripping hashes.
+    #[structopt(long)]
+    raw_symbols: bool,
+
+    /// Merge monomorphizations of generic functions, so `foo::<u8>` and
+    /// `foo::<u32>` become `foo::<_>`.
+    #[structopt(long)]
+    merge_generics: bool,
+
+    /// Show frames of the rust runtime, like `std::rt::lang_start` or
+    /// `core::ops::function::FnOnce::call_once`, which are hidden by default.
+    #[structopt(long)]
+    show_boilerplate: bool,
+
+    /// Hide frames matching this regex. Can be used multiple times.
+    #[structopt(long, value_name = "REGEX", number_of_values = 1)]
+    hide: Vec<Regex>,
+}
+
+impl NormalizeOptions {
+    /// Prepares regexes, so they can be used for each stack.
+    pub fn normalizer(&self) -> Normalizer<'_> {
+        let mut hidden = self.hide.clone();
+        if !self.show_boilerplate {
+            hidden.extend(
+                BOILERPLATE_FRAMES
+                    .iter()
+                    .map(|re| Regex::new(re).expect("invalid regex for boilerplate frames")),
+            );
+        }
+
+        Normalizer {
+            opts: self,
+            hidden,
+            symbols: Symbols::new(),
+        }
+    }
+}
+
+pub struct Normalizer<'a> {
+    opts: &'a NormalizeOptions,
+    hidden: Vec<Regex>,
+    symbols: Symbols,
+}
+
+impl Normalizer<'_> {
+    /// Normalizes frames of a stack, from the root to the leaf.
+    pub fn normalize<'a, I>(&self, frames: I) -> Vec<Cow<'a, str>>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut frames = frames
+            .into_iter()
+            .map(|frame| self.normalize_frame(frame))
+            .collect::<Vec<_>>();
+        self.retain_visible(&mut frames);
+
+        frames
+    }
+
+    /// Same as [Normalizer::normalize], but keeps other information of frames.
+    pub fn normalize_frames(&self, frames: Vec<Frame>) -> Vec<Frame> {
+        let mut frames = frames
+            .into_iter()
+            .map(|mut frame| {
+                if let Cow::Owned(name) = self.normalize_frame(&frame.name) {
+                    frame.name = name;
+                }
+                frame
+            })
+            .collect::<Vec<_>>();
+        self.retain_visible(&mut frames);
+
+        frames
+    }
+
+    /// Removes hidden frames.
+    ///
+    /// The last frame of a stack is never hidden, because time spent by the
+    /// frame itself would be lost.
+    fn retain_visible<S>(&self, frames: &mut Vec<S>)
+    where
+        S: AsRef<str>,
+    {
+        let last = frames.len().saturating_sub(1);
+
+        let mut i = 0;
+        frames.retain(|frame| {
+            let keep = i == last || !self.hidden.iter().any(|re| re.is_match(frame.as_ref()));
+            i += 1;
+            keep
+        });
+    }
+
+    fn normalize_frame<'a>(&self, frame: &'a str) -> Cow<'a, str> {
+        let mut frame = Cow::Borrowed(frame);
+
+        if !self.opts.raw_symbols {
+            frame = self.symbols.demangle(frame);
+        }
+
+        if self.opts.merge_generics {
+            frame = Cow::Owned(merge_generics(&frame));
+        }
+
+        frame
+    }
+}
+
+struct Symbols {
+    /// `::h0123456789abcdef` of legacy symbols.
+    hash: Regex,
+    /// `[0123abcd]` of crate roots of v0 symbols.
+    disambiguator: Regex,
+    /// `{closure#0}` of v0 symbols.
+    closure: Regex,
+    /// Closures in closures.
+    nested_closures: Regex,
+    /// Path of the trait in `<T as core::iter::Iterator>::next`.
+    trait_path: Regex,
+}
+
+impl Symbols {
+    fn new() -> Self {
+        Symbols {
+            hash: Regex::new(r"::h[0-9a-f]{16}\b").unwrap(),
+            disambiguator: Regex::new(r"\[[0-9a-f]+\]::").unwrap(),
+            closure: Regex::new(r"\{closure#[0-9]+\}").unwrap(),
+            nested_closures: Regex::new(r"(::\{\{closure\}\})+").unwrap(),
+            trait_path: Regex::new(r" as (?:[A-Za-z_][A-Za-z0-9_]*::)+").unwrap(),
+        }
+    }
+
+    /// Demangles legacy and v0 symbols, and removes hashes and crate
+    /// disambiguators from already demangled names, so both manglings produce
+    /// the same name.
+    ///
+    /// Closures are named `{{closure}}` like legacy symbols, and nested ones
+    /// are merged. Traits of `<T as Trait>::method` are shortened to their
+    /// names.
+    fn demangle<'a>(&self, frame: Cow<'a, str>) -> Cow<'a, str> {
+        let frame = match try_demangle(&frame) {
+            Ok(demangled) => Cow::Owned(format!("{:#}", demangled)),
+            Err(..) => frame,
+        };
+
+        let frame = replace(frame, &self.hash, "");
+        let frame = replace(frame, &self.disambiguator, "::");
+        let frame = replace(frame, &self.closure, "{{closure}}");
+        let frame = replace(frame, &self.nested_closures, "::{{closure}}");
+        replace(frame, &self.trait_path, " as ")
+    }
+}
+
+fn replace<'a>(frame: Cow<'a, str>, re: &Regex, rep: &str) -> Cow<'a, str> {
+    match re.replace_all(&frame, rep) {
+        Cow::Borrowed(..) => frame,
+        Cow::Owned(s) => Cow::Owned(s),
+    }
+}
+
+/// Replaces generic arguments like `::<u8>` with `::<_>`.
+fn merge_generics(frame: &str) -> String {
+    let mut buf = String::with_capacity(frame.len());
+    let mut rest = frame;
+
+    while let Some(start) = rest.find("::<") {
+        buf.push_str(&rest[..start]);
+        buf.push_str("::<_>");
+
+        let mut depth = 0;
+        let mut end = rest.len();
+        let bytes = rest.as_bytes();
+        for i in start + 2..bytes.len() {
+            match bytes[i] {
+                b'<' => depth += 1,
+                // `->` of function pointers
+                b'>' if bytes[i - 1] == b'-' => {}
+                b'>' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = i + 1;
+                        break;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        rest = &rest[end..];
+    }
+
+    buf.push_str(rest);
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::StackOptions;
+    use super::*;
+
+    fn opts(args: &[&str]) -> StackOptions {
+        StackOptions::from_iter(std::iter::once("stacks").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn hashes_are_stripped() {
+        let out = opts(&[]).apply(
+            b"a;_ZN6simple4main17h0123456789abcdefE 1\na;simple::main::h0123456789abcdef 2\n",
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), "a;simple::main 3\n");
+    }
+
+    #[test]
+    fn v0_symbols_are_demangled() {
+        let out = opts(&[]).apply(b"_RNvCs1234_7mycrate3foo 1\nmycrate[1234abcd]::foo 1\n");
+        assert_eq!(String::from_utf8(out).unwrap(), "mycrate::foo 2\n");
+    }
+
+    #[test]
+    fn bracketed_hex_is_kept_outside_of_crate_roots() {
+        let out = opts(&[]).apply(b"[deadbeef];foo::<[u8; 16]>::bar 1\n");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "[deadbeef];foo::<[u8; 16]>::bar 1\n"
+        );
+    }
+
+    #[test]
+    fn closures_are_merged() {
+        let out = opts(&[]).apply(
+            b"main;mycrate[1234abcd]::run::{closure#0}::{closure#1} 1\n\
+              main;mycrate::run::{{closure}}::h0123456789abcdef 2\n",
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "main;mycrate::run::{{closure}} 3\n"
+        );
+    }
+
+    #[test]
+    fn trait_paths_are_shortened() {
+        let out = opts(&[]).apply(
+            b"main;<alloc::vec::Vec<u8> as core::iter::traits::collect::Extend<&u8>>::extend 1\n",
+        );
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "main;<alloc::vec::Vec<u8> as Extend<&u8>>::extend 1\n"
+        );
+
+        let out = opts(&[]).apply(
+            b"main;<parser::Parser as core::ops::function::FnOnce<()>>::call_once;parse 1\n",
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), "main;parse 1\n");
+    }
+
+    #[test]
+    fn generics_are_merged() {
+        assert_eq!(
+            merge_generics("foo::<alloc::vec::Vec<u8>>::bar::<fn() -> u8>"),
+            "foo::<_>::bar::<_>"
+        );
+
+        let out = opts(&["--merge-generics"]).apply(b"main;foo::<u8> 1\nmain;foo::<u32> 2\n");
+        assert_eq!(String::from_utf8(out).unwrap(), "main;foo::<_> 3\n");
+    }
+
+    #[test]
+    fn boilerplate_is_hidden() {
+        let input = b"std::rt::lang_start;core::ops::function::FnOnce::call_once;main;foo 1\n";
+
+        let out = opts(&[]).apply(input);
+        assert_eq!(String::from_utf8(out).unwrap(), "main;foo 1\n");
+
+        let out = opts(&["--show-boilerplate", "--hide", "^foo$"]).apply(input);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "std::rt::lang_start;core::ops::function::FnOnce::call_once;main;foo 1\n"
+        );
+    }
+}
@@ -0,0 +1,188 @@
+//! Weighs stacks by the time spent on and off cpus.
+
+use super::to_collapsed;
+use super::ContextSwitch;
+use super::Sample;
+use crate::export::group_by_thread;
+use crate::export::sampling_interval;
+use std::collections::HashMap;
+
+/// The tracepoint hit when a thread is switched out. Its stack is where the
+/// thread blocked.
+pub const SCHED_SWITCH: &str = "sched:sched_switch";
+
+/// Frame appended to stacks blocked off the cpu, in wall-clock views.
+const OFF_CPU_FRAME: &str = "[off-cpu]";
+
+/// Kind of time used as weights of stacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clock {
+    /// Time spent running on cpus.
+    OnCpu,
+    /// Time spent blocked, waiting for locks, io or the scheduler.
+    OffCpu,
+    /// Sum of on-cpu and off-cpu time.
+    Wall,
+}
+
+/// Returns collapsed stacks weighted by microseconds.
+///
+/// Samples of [SCHED_SWITCH] are blocked until the thread is switched in, or
+/// until the next sample of the thread if switches are not recorded. Other
+/// samples are on-cpu for their period if the event is a clock, or for the
+/// sampling interval otherwise.
+///
+/// The root frame of each stack is the name of the thread, or
+/// `{name}-{pid}/{tid}` if `threads` is true.
+pub fn to_collapsed_by_time(
+    samples: &[Sample],
+    switches: &[ContextSwitch],
+    clock: Clock,
+    threads: bool,
+) -> Vec<u8> {
+    let stack_of = |sample: &Sample, off_cpu: bool| {
+        let comm = sample.comm.replace(' ', "_");
+        let mut stack = if threads {
+            format!("{}-{}/{}", comm, sample.pid, sample.tid)
+        } else {
+            comm
+        };
+        for frame in &sample.stack {
+            stack.push(';');
+            stack.push_str(&frame.name);
+        }
+        if off_cpu && clock == Clock::Wall {
+            stack.push(';');
+            stack.push_str(OFF_CPU_FRAME);
+        }
+        stack
+    };
+
+    let mut stacks = vec![];
+
+    if clock != Clock::OffCpu {
+        let on_cpu = samples.iter().filter(|s| !is_sched_switch(s));
+        let interval = sampling_interval(&group_by_thread(on_cpu.clone()));
+
+        for sample in on_cpu {
+            let secs = match sample.event.as_deref().and_then(|e| e.split(':').next()) {
+                // Periods of these events are cpu time in nanoseconds.
+                Some("cpu-clock") | Some("task-clock") => sample.period as f64 / 1e9,
+                _ => interval,
+            };
+            stacks.push((stack_of(sample, false), micros(secs)));
+        }
+    }
+
+    if clock != Clock::OnCpu {
+        for (sample, secs) in blocked_durations(samples, switches) {
+            stacks.push((stack_of(sample, true), micros(secs)));
+        }
+    }
+
+    to_collapsed(stacks.into_iter().filter(|&(_, micros)| micros != 0))
+}
+
+/// Returns true if `samples` contains samples of [SCHED_SWITCH].
+pub fn has_sched_switch(samples: &[Sample]) -> bool {
+    samples.iter().any(is_sched_switch)
+}
+
+fn is_sched_switch(sample: &Sample) -> bool {
+    sample.event.as_deref() == Some(SCHED_SWITCH)
+}
+
+fn micros(secs: f64) -> usize {
+    (secs * 1e6).round() as usize
+}
+
+/// Returns samples of [SCHED_SWITCH] with the duration the thread was
+/// blocked, in seconds.
+///
+/// A thread is blocked until it's switched in, or until its next sample.
+/// Samples are dropped if the thread did not run again.
+fn blocked_durations<'a>(
+    samples: &'a [Sample],
+    switches: &[ContextSwitch],
+) -> Vec<(&'a Sample, f64)> {
+    let mut wakeups = HashMap::<_, Vec<f64>>::new();
+    for switch in switches.iter().filter(|s| !s.out) {
+        wakeups
+            .entry((switch.pid, switch.tid))
+            .or_default()
+            .push(switch.time);
+    }
+    for sample in samples.iter().filter(|s| !is_sched_switch(s)) {
+        wakeups
+            .entry((sample.pid, sample.tid))
+            .or_default()
+            .push(sample.time);
+    }
+    for times in wakeups.values_mut() {
+        times.sort_by(f64::total_cmp);
+    }
+
+    samples
+        .iter()
+        .filter(|s| is_sched_switch(s))
+        .filter_map(|sample| {
+            let times = wakeups.get(&(sample.pid, sample.tid))?;
+            let idx = times.partition_point(|&t| t <= sample.time);
+            let wakeup = times.get(idx)?;
+
+            Some((sample, wakeup - sample.time))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    fn sample(time: f64, event: &str, stack: &[&str]) -> Sample {
+        Sample {
+            event: Some(Arc::from(event)),
+            period: 1_000_000,
+            ..Sample::new(1, time, stack)
+        }
+    }
+
+    #[test]
+    fn blocked_until_switched_in() {
+        let samples = [
+            sample(1.0, "task-clock", &["main", "parse"]),
+            sample(1.001, SCHED_SWITCH, &["main", "read"]),
+            sample(1.5, "task-clock", &["main", "parse"]),
+            sample(2.0, SCHED_SWITCH, &["main", "lock"]),
+        ];
+        let switches = [
+            ContextSwitch {
+                pid: 1,
+                tid: 1,
+                time: 1.201,
+                out: false,
+                preempted: false,
+            },
+            ContextSwitch {
+                pid: 1,
+                tid: 1,
+                time: 2.0,
+                out: true,
+                preempted: false,
+            },
+        ];
+
+        let off_cpu = to_collapsed_by_time(&samples, &switches, Clock::OffCpu, false);
+        assert_eq!(
+            String::from_utf8(off_cpu).unwrap(),
+            "main;main;read 200000\n"
+        );
+
+        let wall = to_collapsed_by_time(&samples, &switches, Clock::Wall, true);
+        assert_eq!(
+            String::from_utf8(wall).unwrap(),
+            "main-1/1;main;parse 2000\nmain-1/1;main;read;[off-cpu] 200000\n"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Locations of addresses stored across runs, per build-id.
+//!
+//! Files are stored in `$XDG_CACHE_HOME/cargo-profile/symbols`, or
+//! `~/.cache/cargo-profile/symbols`.
+
+use super::Location;
+use anyhow::Context;
+use anyhow::Error;
+use serde_json::json;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+/// Bumped if the format of files or the meaning of locations changes.
+const VERSION: u32 = 2;
+
+fn path(build_id: &str) -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(env::var_os("HOME")?).join(".cache"),
+    };
+
+    Some(
+        dir.join("cargo-profile")
+            .join("symbols")
+            .join(format!("{}.v{}.json", build_id, VERSION)),
+    )
+}
+
+/// Returns an empty map if nothing is stored.
+pub(super) fn load(build_id: &str) -> HashMap<u64, Vec<Location>> {
+    path(build_id)
+        .and_then(|path| fs::read(path).ok())
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .map(|json| from_json(&json))
+        .unwrap_or_default()
+}
+
+pub(super) fn store(build_id: &str, locations: &HashMap<u64, Vec<Location>>) -> Result<(), Error> {
+    let path = path(build_id).context("failed to find the cache directory")?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    // Written to a temporary file first, as other runs may read it.
+    let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+    fs::write(&tmp, serde_json::to_vec(&to_json(locations))?)?;
+    fs::rename(&tmp, &path)?;
+
+    Ok(())
+}
+
+/// `{"1a2b": [["name", "src/lib.rs", 10]]}`
+fn to_json(locations: &HashMap<u64, Vec<Location>>) -> Value {
+    locations
+        .iter()
+        .map(|(address, locations)| {
+            let locations = locations
+                .iter()
+                .map(|l| json!([l.name, l.file, l.line]))
+                .collect();
+            (format!("{:x}", address), Value::Array(locations))
+        })
+        .collect::<serde_json::Map<_, _>>()
+        .into()
+}
+
+fn from_json(json: &Value) -> HashMap<u64, Vec<Location>> {
+    let object = match json.as_object() {
+        Some(object) => object,
+        None => return Default::default(),
+    };
+
+    object
+        .iter()
+        .filter_map(|(address, locations)| {
+            let address = u64::from_str_radix(address, 16).ok()?;
+            let locations = locations
+                .as_array()?
+                .iter()
+                .map(|l| {
+                    Some(Location {
+                        name: l.get(0)?.as_str()?.to_string(),
+                        file: l.get(1)?.as_str().map(String::from),
+                        line: l.get(2)?.as_u64().map(|line| line as u32),
+                    })
+                })
+                .collect::<Option<Vec<_>>>()?;
+
+            Some((address, locations))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn json_roundtrip() {
+        let mut locations = HashMap::new();
+        locations.insert(
+            0x1a2b,
+            vec![
+                Location {
+                    name: "_ZN4main4mainE".into(),
+                    file: Some("src/main.rs".into()),
+                    line: Some(10),
+                },
+                Location {
+                    name: "inlined".into(),
+                    file: None,
+                    line: None,
+                },
+            ],
+        );
+        locations.insert(0x10, vec![]);
+
+        assert_eq!(from_json(&to_json(&locations)), locations);
+    }
+}
@@ -0,0 +1,240 @@
+//! Reads DWARF of binaries, including split debuginfo.
+
+use super::Location;
+use crate::util::read_shared;
+use addr2line::Context;
+use addr2line::LookupContinuation;
+use addr2line::LookupResult;
+use addr2line::SplitDwarfLoad;
+use anyhow::Error;
+use gimli::EndianArcSlice;
+use gimli::Reader as _;
+use gimli::RunTimeEndian;
+use gimli::SectionId;
+use object::CompressionFormat;
+use object::Object;
+use object::ObjectSection;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+type Reader = EndianArcSlice<RunTimeEndian>;
+
+/// Sections of a debug file, which are loaded once and shared by threads.
+pub(super) struct DebugFile {
+    sections: gimli::DwarfSections<Reader>,
+    /// Sections of the `.dwp` file, which contains split units of the binary.
+    package: Option<gimli::DwarfPackageSections<Reader>>,
+    endian: RunTimeEndian,
+}
+
+impl DebugFile {
+    /// Loads DWARF of `binary`, from the first file containing it:
+    ///
+    ///  - `.dSYM` bundles in `extra_files`.
+    ///  - `binary` itself.
+    ///  - `/usr/lib/debug/.build-id/`, where distributions install debuginfo.
+    ///
+    /// Returns [None] if DWARF is not found.
+    pub fn load(
+        binary: &Path,
+        build_id: Option<&str>,
+        extra_files: &[PathBuf],
+    ) -> Result<Option<Self>, Error> {
+        let mut candidates = extra_files
+            .iter()
+            .filter(|path| path.extension() == Some("dSYM".as_ref()))
+            .filter_map(|path| {
+                let dir = path.join("Contents").join("Resources").join("DWARF");
+                let entry = fs::read_dir(dir).ok()?.next()?.ok()?;
+                Some(entry.path())
+            })
+            .collect::<Vec<_>>();
+        candidates.push(binary.to_path_buf());
+        if let Some(id) = build_id.filter(|id| id.len() > 2) {
+            candidates.push(
+                Path::new("/usr/lib/debug/.build-id")
+                    .join(&id[..2])
+                    .join(format!("{}.debug", &id[2..])),
+            );
+        }
+
+        for path in candidates {
+            let data = match read_shared(&path) {
+                Ok(data) => data,
+                Err(..) => continue,
+            };
+            let file = match object::File::parse(&*data) {
+                Ok(file) => file,
+                Err(..) => continue,
+            };
+            if file.section_by_name(".debug_info").is_none() {
+                continue;
+            }
+
+            let endian = endian(&file);
+            let sections = gimli::DwarfSections::load(|id: SectionId| {
+                section(&data, &file, Some(id.name()), endian)
+            })?;
+
+            return Ok(Some(DebugFile {
+                sections,
+                package: load_package(binary, extra_files),
+                endian,
+            }));
+        }
+
+        Ok(None)
+    }
+}
+
+/// DWARF of a binary, which is parsed lazily.
+///
+/// This cannot be shared by threads, so each thread creates its own one from
+/// a [DebugFile].
+pub(super) struct DebugInfo {
+    context: Context<Reader>,
+    package: Option<gimli::DwarfPackage<Reader>>,
+}
+
+impl DebugInfo {
+    pub fn new(file: &DebugFile) -> Result<Self, Error> {
+        let context = Context::from_dwarf(file.sections.borrow(|section| section.clone()))?;
+        let package = file.package.as_ref().and_then(|package| {
+            package
+                .borrow(
+                    |section| section.clone(),
+                    Reader::new(Arc::from(&[][..]), file.endian),
+                )
+                .ok()
+        });
+
+        Ok(DebugInfo { context, package })
+    }
+
+    /// Returns functions at `address`, from the outermost function to the
+    /// innermost inlined function.
+    pub fn locations(&self, address: u64) -> Vec<Location> {
+        let mut lookup = self.context.find_frames(address);
+        let frames = loop {
+            match lookup {
+                LookupResult::Output(frames) => break frames,
+                LookupResult::Load { load, continuation } => {
+                    lookup = continuation.resume(self.load_split_unit(&load));
+                }
+            }
+        };
+
+        let mut frames = match frames {
+            Ok(frames) => frames,
+            Err(..) => return vec![],
+        };
+        let mut locations = vec![];
+        while let Ok(Some(frame)) = frames.next() {
+            let name = match frame.function.as_ref().map(|f| f.raw_name()) {
+                Some(Ok(name)) => name.into_owned(),
+                _ => continue,
+            };
+            let location = frame.location.as_ref();
+
+            locations.push(Location {
+                name,
+                file: location.and_then(|l| l.file).map(String::from),
+                line: location.and_then(|l| l.line),
+            });
+        }
+        locations.reverse();
+
+        locations
+    }
+
+    /// Loads a split unit from the `.dwp` file or a `.dwo` file.
+    fn load_split_unit(&self, load: &SplitDwarfLoad<Reader>) -> Option<Arc<gimli::Dwarf<Reader>>> {
+        if let Some(package) = &self.package {
+            return package
+                .find_cu(load.dwo_id, &load.parent)
+                .ok()?
+                .map(Arc::new);
+        }
+
+        let mut path = PathBuf::new();
+        if let Some(dir) = &load.comp_dir {
+            path.push(&*dir.to_string_lossy().ok()?);
+        }
+        path.push(&*load.path.as_ref()?.to_string_lossy().ok()?);
+
+        let data = read_shared(&path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+        let mut dwarf = load_dwo(&data, &file).ok()?;
+        dwarf.make_dwo(&load.parent);
+
+        Some(Arc::new(dwarf))
+    }
+}
+
+/// Loads sections of the `.dwp` file in `extra_files`, or next to `binary`.
+fn load_package(
+    binary: &Path,
+    extra_files: &[PathBuf],
+) -> Option<gimli::DwarfPackageSections<Reader>> {
+    let mut next_to_binary = binary.as_os_str().to_owned();
+    next_to_binary.push(".dwp");
+
+    let path = extra_files
+        .iter()
+        .find(|path| path.extension() == Some("dwp".as_ref()))
+        .cloned()
+        .unwrap_or_else(|| next_to_binary.into());
+
+    let data = read_shared(&path).ok()?;
+    let file = object::File::parse(&*data).ok()?;
+    let endian = endian(&file);
+
+    gimli::DwarfPackageSections::load(|id: SectionId| section(&data, &file, id.dwo_name(), endian))
+        .ok()
+}
+
+fn load_dwo(data: &Arc<[u8]>, file: &object::File) -> Result<gimli::Dwarf<Reader>, Error> {
+    let endian = endian(file);
+
+    gimli::Dwarf::load(|id: SectionId| section(data, file, id.dwo_name(), endian))
+}
+
+/// Returns the data of a section, or an empty one if it does not exist.
+///
+/// Uncompressed sections refer to `data`, which `file` is parsed from, so
+/// they are not copied.
+fn section(
+    data: &Arc<[u8]>,
+    file: &object::File,
+    name: Option<&str>,
+    endian: RunTimeEndian,
+) -> Result<Reader, Error> {
+    let section = match name.and_then(|name| file.section_by_name(name)) {
+        Some(section) => section,
+        None => return Ok(Reader::new(Arc::from(&[][..]), endian)),
+    };
+
+    let range = section.compressed_file_range()?;
+    if range.format == CompressionFormat::None {
+        let start = range.offset as usize;
+        let end = start.saturating_add(range.uncompressed_size as usize);
+        if data.get(start..end).is_some() {
+            return Ok(Reader::new(data.clone(), endian).range(start..end));
+        }
+    }
+
+    Ok(Reader::new(
+        Arc::from(&*section.uncompressed_data()?),
+        endian,
+    ))
+}
+
+fn endian(file: &object::File) -> RunTimeEndian {
+    if file.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    }
+}
@@ -0,0 +1,288 @@
+//! Resolves addresses of binaries to functions, inlined functions and source
+//! lines, using debuginfo.
+
+use self::dwarf::DebugFile;
+use self::workers::Job;
+use self::workers::Workers;
+use crate::cargo::BinFile;
+use crate::util::read_shared;
+use object::Object;
+use std::collections::HashMap;
+use std::iter;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::Arc;
+
+mod cache;
+mod dwarf;
+mod workers;
+
+/// A function at an address, and the source line of the address in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Location {
+    /// Mangled name, if debuginfo has it.
+    pub name: String,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+}
+
+/// Symbolizes addresses of many binaries, loading each of them once.
+///
+/// Locations are cached per build-id on disk, so debuginfo is parsed only for
+/// addresses not seen by previous runs. Addresses missing in the cache are
+/// resolved by a thread per cpu.
+#[derive(Default)]
+pub(crate) struct Symbolizer {
+    /// Debug files of binaries built by cargo, like `.dSYM` or `.dwp`.
+    extra_files: HashMap<PathBuf, Vec<PathBuf>>,
+    binaries: HashMap<PathBuf, Option<Binary>>,
+    /// Spawned when debuginfo is read for the first time.
+    workers: Option<Workers>,
+}
+
+struct Binary {
+    path: PathBuf,
+    build_id: Option<String>,
+    /// Loaded on the first address missing in the cache.
+    debug_file: Option<Option<Arc<DebugFile>>>,
+    /// From the outermost function to the innermost inlined function, by
+    /// addresses. Empty if debuginfo does not cover the address.
+    locations: HashMap<u64, Vec<Location>>,
+    /// True if `locations` has addresses resolved using debuginfo, which are
+    /// not stored on disk.
+    dirty: bool,
+}
+
+impl Symbolizer {
+    pub fn new(binaries: &[BinFile]) -> Self {
+        Symbolizer {
+            extra_files: binaries
+                .iter()
+                .map(|file| (file.path.clone(), file.extra_files.clone()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Returns functions at `address` of `binary`, from the outermost
+    /// function to the innermost inlined function.
+    ///
+    /// `address` is relative to the base address of the binary, like
+    /// addresses of symbols. The result is empty if debuginfo is not
+    /// available.
+    ///
+    /// Use [Symbolizer::symbolize_all] first to resolve many addresses at
+    /// once.
+    pub fn symbolize(&mut self, binary: &Path, address: u64) -> &[Location] {
+        self.symbolize_all(iter::once((binary, address)));
+
+        match self.binaries.get(binary) {
+            Some(Some(binary)) => binary
+                .locations
+                .get(&address)
+                .map(|locations| &**locations)
+                .unwrap_or_default(),
+            _ => &[],
+        }
+    }
+
+    /// Resolves addresses missing in the cache in parallel, so
+    /// [Symbolizer::symbolize] returns them without reading debuginfo.
+    pub fn symbolize_all<'a, I>(&mut self, addresses: I)
+    where
+        I: IntoIterator<Item = (&'a Path, u64)>,
+    {
+        let mut missing = HashMap::<&Path, Vec<u64>>::new();
+        for (path, address) in addresses {
+            if !self.binaries.contains_key(path) {
+                self.binaries.insert(path.to_path_buf(), Binary::open(path));
+            }
+            if let Some(binary) = &self.binaries[path] {
+                if !binary.locations.contains_key(&address) {
+                    missing.entry(path).or_default().push(address);
+                }
+            }
+        }
+        if missing.is_empty() {
+            return;
+        }
+
+        let workers = self.workers.get_or_insert_with(Workers::spawn);
+        let (reply, replies) = mpsc::channel();
+        for (path, mut addresses) in missing {
+            let binary = match self.binaries.get_mut(path) {
+                Some(Some(binary)) => binary,
+                _ => continue,
+            };
+            let file = match binary.debug_file(&self.extra_files) {
+                Some(file) => file,
+                None => {
+                    // Not stored, as debuginfo may be installed later without
+                    // changing the build-id.
+                    binary
+                        .locations
+                        .extend(addresses.into_iter().map(|address| (address, vec![])));
+                    continue;
+                }
+            };
+            binary.dirty = true;
+
+            // Each thread gets near addresses, which are mostly in the same
+            // compilation units.
+            addresses.sort_unstable();
+            addresses.dedup();
+            let chunk = addresses.len().div_ceil(workers.threads());
+            for addresses in addresses.chunks(chunk) {
+                workers.send(Job {
+                    binary: path.to_path_buf(),
+                    file: file.clone(),
+                    addresses: addresses.to_vec(),
+                    reply: reply.clone(),
+                });
+            }
+        }
+        drop(reply);
+
+        for (path, locations) in replies {
+            if let Some(Some(binary)) = self.binaries.get_mut(&path) {
+                binary.locations.extend(locations);
+            }
+        }
+    }
+
+    /// Stores new locations to the cache.
+    pub fn save(&self) {
+        for binary in self.binaries.values().flatten() {
+            if let (true, Some(build_id)) = (binary.dirty, &binary.build_id) {
+                // The cache is only for speed.
+                let _ = cache::store(build_id, &binary.locations);
+            }
+        }
+    }
+}
+
+impl Binary {
+    fn open(path: &Path) -> Option<Self> {
+        let data = read_shared(path).ok()?;
+        let file = object::File::parse(&*data).ok()?;
+        let build_id = build_id(&file);
+
+        let locations = build_id.as_deref().map(cache::load).unwrap_or_default();
+
+        Some(Binary {
+            path: path.to_path_buf(),
+            build_id,
+            debug_file: None,
+            locations,
+            dirty: false,
+        })
+    }
+
+    fn debug_file(
+        &mut self,
+        extra_files: &HashMap<PathBuf, Vec<PathBuf>>,
+    ) -> Option<Arc<DebugFile>> {
+        let path = &self.path;
+        let build_id = self.build_id.as_deref();
+
+        self.debug_file
+            .get_or_insert_with(|| {
+                let extra_files = extra_files.get(path).map(|v| &**v).unwrap_or_default();
+                DebugFile::load(path, build_id, extra_files)
+                    .ok()
+                    .flatten()
+                    .map(Arc::new)
+            })
+            .clone()
+    }
+}
+
+/// Returns the build-id of ELF files, or the UUID of Mach-O files, as a hex
+/// string.
+fn build_id(file: &object::File) -> Option<String> {
+    let id = match file.build_id() {
+        Ok(Some(id)) => id.to_vec(),
+        _ => file.mach_uuid().ok()??.to_vec(),
+    };
+
+    Some(id.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::dwarf::DebugInfo;
+    use super::*;
+    use object::ObjectSymbol;
+    use std::env;
+    use std::fs;
+
+    #[inline(never)]
+    fn target() -> u32 {
+        42
+    }
+
+    #[test]
+    fn own_functions_have_source_lines() {
+        assert_eq!(target(), 42);
+
+        // Addresses of the test binary start from 0, as it's built as a PIE.
+        let exe = env::current_exe().unwrap();
+        let data = fs::read(&exe).unwrap();
+        let file = object::File::parse(&*data).unwrap();
+        let address = file
+            .symbols()
+            .find(|sym| {
+                sym.name()
+                    .unwrap_or_default()
+                    .contains("symbolize4test6target")
+            })
+            .map(|sym| sym.address())
+            .unwrap();
+
+        let debug_file = DebugFile::load(&exe, build_id(&file).as_deref(), &[])
+            .unwrap()
+            .unwrap();
+        let locations = DebugInfo::new(&debug_file).unwrap().locations(address);
+
+        let location = locations.last().unwrap();
+        assert!(location.name.contains("target"), "{:?}", locations);
+        assert!(
+            location
+                .file
+                .as_deref()
+                .unwrap()
+                .ends_with("src/symbolize/mod.rs"),
+            "{:?}",
+            locations
+        );
+        assert!(location.line.is_some());
+    }
+
+    #[test]
+    fn threads_resolve_same_locations() {
+        let exe = env::current_exe().unwrap();
+        let data = fs::read(&exe).unwrap();
+        let file = object::File::parse(&*data).unwrap();
+        let addresses = file
+            .symbols()
+            .filter(|sym| sym.kind() == object::SymbolKind::Text && sym.size() != 0)
+            .map(|sym| sym.address())
+            .take(256)
+            .collect::<Vec<_>>();
+
+        let mut symbolizer = Symbolizer::default();
+        symbolizer.symbolize_all(addresses.iter().map(|&address| (&*exe, address)));
+
+        let debug_file = DebugFile::load(&exe, build_id(&file).as_deref(), &[])
+            .unwrap()
+            .unwrap();
+        let debug_info = DebugInfo::new(&debug_file).unwrap();
+        for address in addresses {
+            assert_eq!(
+                symbolizer.symbolize(&exe, address),
+                &*debug_info.locations(address)
+            );
+        }
+    }
+}
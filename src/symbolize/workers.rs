@@ -0,0 +1,88 @@
+//! Threads reading debuginfo.
+//!
+//! Contexts of `addr2line` cannot be shared by threads, so each thread creates
+//! its own one for each binary, from sections loaded once.
+
+use super::dwarf::DebugFile;
+use super::dwarf::DebugInfo;
+use super::Location;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+/// Locations of addresses of a binary.
+pub(super) type Reply = (PathBuf, Vec<(u64, Vec<Location>)>);
+
+pub(super) struct Job {
+    pub binary: PathBuf,
+    pub file: Arc<DebugFile>,
+    pub addresses: Vec<u64>,
+    pub reply: Sender<Reply>,
+}
+
+/// A thread per cpu. Threads exit when this is dropped.
+pub(super) struct Workers {
+    jobs: Sender<Job>,
+    threads: usize,
+}
+
+impl Workers {
+    pub fn spawn() -> Self {
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+
+        let (jobs, rx) = mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            let rx = rx.clone();
+            thread::spawn(move || work(&rx));
+        }
+
+        Workers { jobs, threads }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// The locations are sent to [Job::reply].
+    pub fn send(&self, job: Job) {
+        // Threads exit only if they panicked, and then addresses of the job
+        // are left unresolved.
+        let _ = self.jobs.send(job);
+    }
+}
+
+fn work(jobs: &Mutex<Receiver<Job>>) {
+    let mut contexts = HashMap::<PathBuf, Option<DebugInfo>>::new();
+
+    loop {
+        let job = match jobs.lock().map(|jobs| jobs.recv()) {
+            Ok(Ok(job)) => job,
+            _ => return,
+        };
+
+        let debug_info = contexts
+            .entry(job.binary.clone())
+            .or_insert_with(|| DebugInfo::new(&job.file).ok());
+        let locations = job
+            .addresses
+            .iter()
+            .map(|&address| {
+                let locations = match debug_info {
+                    Some(debug_info) => debug_info.locations(address),
+                    None => vec![],
+                };
+                (address, locations)
+            })
+            .collect();
+
+        let _ = job.reply.send((job.binary, locations));
+    }
+}
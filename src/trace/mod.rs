@@ -1,8 +1,15 @@
 use crate::cargo::compile;
 use crate::cargo::CargoTarget;
+use crate::cli_tools::profiler::profile;
+use crate::cli_tools::profiler::ProfileTarget;
+use crate::cli_tools::profiler::RecordOptions;
+use crate::export::Format;
+use crate::export::Profile;
+use crate::stacks::StackOptions;
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Error;
+use std::path::PathBuf;
 use structopt::StructOpt;
 use xctrace::run_xctrace;
 
@@ -24,6 +31,10 @@ impl TraceCommand {
     pub fn run(self) -> Result<(), Error> {
         let Self { root, tool } = self;
 
+        if let TraceTool::Dtrace { .. } = tool {
+            bail!("`cargo profile trace dtrace` is not implemented yet")
+        }
+
         let target = tool.target();
 
         let binaries = compile(target).context("cargo execution failed")?;
@@ -37,9 +48,38 @@ impl TraceCommand {
 
         let binary = binaries.into_iter().next().unwrap();
 
-        match tool {
-            TraceTool::Dtrace { .. } => {}
-            TraceTool::Perf { .. } => {}
+        match &tool {
+            TraceTool::Dtrace { .. } => unreachable!(),
+            TraceTool::Perf {
+                record,
+                stacks,
+                output,
+                save_raw,
+                ..
+            } => {
+                if !cfg!(target_os = "linux") {
+                    bail!("`cargo profile trace perf` is supported only on linux")
+                }
+
+                let record = RecordOptions {
+                    switch_events: true,
+                    ..record.clone()
+                };
+                let recording = profile(
+                    root,
+                    ProfileTarget::Binary {
+                        file: &binary,
+                        args: target.args(),
+                    },
+                    &record,
+                    save_raw.as_deref(),
+                )?;
+
+                let profile =
+                    Profile::load(Format::Chrome, &recording, stacks, binary.name.clone())?;
+                Format::Chrome.write(&profile, output)?;
+                eprintln!("Wrote trace events to {}", output.display());
+            }
             TraceTool::Xctrace { .. } => {
                 run_xctrace(root, &binary, target.args()).context("failed to run xctrace")?;
             }
@@ -51,23 +91,46 @@ impl TraceCommand {
 
 /// Tool used to generate trace.
 #[derive(Debug, Clone, StructOpt)]
+#[allow(clippy::large_enum_variant)]
 pub enum TraceTool {
-    /// WIP
+    /// Not implemented yet.
     Dtrace {
         /// Compile library
-        #[structopt(subcommand)]
+        #[structopt(flatten)]
         target: CargoTarget,
     },
-    /// WIP
+    /// Invokes perf to record samples and context switches, and creates trace
+    /// events of chrome, which can be opened using https://ui.perfetto.dev or
+    /// `about://tracing`.
     Perf {
+        /// Path to the output json file. Use `-` to write to stdout.
+        #[structopt(
+            short = "o",
+            long,
+            parse(from_os_str),
+            value_name = "PATH",
+            default_value = "trace.json"
+        )]
+        output: PathBuf,
+
+        /// Store `perf.data` at this path instead of a temporary directory.
+        #[structopt(long, parse(from_os_str), value_name = "PATH")]
+        save_raw: Option<PathBuf>,
+
+        #[structopt(flatten)]
+        record: RecordOptions,
+
+        #[structopt(flatten)]
+        stacks: StackOptions,
+
         /// Compile library
-        #[structopt(subcommand)]
+        #[structopt(flatten)]
         target: CargoTarget,
     },
     /// Invokes xctrace to create `.trace` file.
     Xctrace {
         /// Compile library
-        #[structopt(subcommand)]
+        #[structopt(flatten)]
         target: CargoTarget,
     },
 }
@@ -76,7 +139,7 @@ impl TraceTool {
     pub fn target(&self) -> &CargoTarget {
         match self {
             TraceTool::Dtrace { target }
-            | TraceTool::Perf { target }
+            | TraceTool::Perf { target, .. }
             | TraceTool::Xctrace { target } => target,
         }
     }
@@ -1,4 +1,20 @@
+use anyhow::Context;
+use anyhow::Error;
+use std::collections::HashMap;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufRead;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
 use std::process::Command;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+use std::sync::Weak;
+use std::time::Duration;
 
 pub fn command(root: bool, cmd: &str) -> Command {
     if root {
@@ -9,3 +25,108 @@ pub fn command(root: bool, cmd: &str) -> Command {
         Command::new(cmd)
     }
 }
+
+/// Parses durations like `30s`, `500ms`, `2m` or `1.5`, which is in seconds.
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(idx) => s.split_at(idx),
+        None => (s, "s"),
+    };
+
+    let value = value
+        .parse::<f64>()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let secs = match unit {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return Err(format!("invalid unit of duration: {}", s)),
+    };
+
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("invalid duration {}: {}", s, e))
+}
+
+/// Opens `path` for writing. `-` means stdout.
+pub(crate) fn open_output(path: &Path) -> Result<Box<dyn Write>, Error> {
+    if path == Path::new("-") {
+        return Ok(Box::new(BufWriter::new(io::stdout())));
+    }
+
+    let file = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("unable to create output file {}", path.display()))?;
+
+    Ok(Box::new(BufWriter::new(file)))
+}
+
+/// Calls `f` with each line of `reader`, without the line ending.
+///
+/// Lines are read one by one, so large outputs of profilers are not kept in
+/// memory. Invalid UTF-8 is replaced, like [String::from_utf8_lossy].
+pub(crate) fn for_each_line(mut reader: impl BufRead, mut f: impl FnMut(&str)) -> io::Result<()> {
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(());
+        }
+
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        f(line.strip_suffix('\r').unwrap_or(line));
+    }
+}
+
+/// Reads a file, or returns the data read already if it's still used.
+///
+/// Binaries are used for symbol tables, unwinding and debuginfo, and debug
+/// builds may be gigabytes, so they are kept in memory once.
+pub(crate) fn read_shared(path: &Path) -> io::Result<Arc<[u8]>> {
+    static FILES: OnceLock<Mutex<HashMap<PathBuf, Weak<[u8]>>>> = OnceLock::new();
+
+    let mut files = FILES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|err| err.into_inner());
+    if let Some(data) = files.get(path).and_then(Weak::upgrade) {
+        return Ok(data);
+    }
+
+    let data = Arc::<[u8]>::from(fs::read(path)?);
+    files.retain(|_, data| data.strong_count() != 0);
+    files.insert(path.to_path_buf(), Arc::downgrade(&data));
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn files_in_use_are_read_once() {
+        let exe = std::env::current_exe().unwrap();
+
+        let data = read_shared(&exe).unwrap();
+        assert!(Arc::ptr_eq(&data, &read_shared(&exe).unwrap()));
+    }
+
+    #[test]
+    fn durations_can_be_parsed() {
+        assert_eq!(parse_duration("30s").unwrap(), Duration::from_secs(30));
+        assert_eq!(parse_duration("500ms").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("2m").unwrap(), Duration::from_secs(120));
+        assert_eq!(parse_duration("1.5").unwrap(), Duration::from_millis(1500));
+        assert!(parse_duration("1d").is_err());
+    }
+
+    #[test]
+    fn huge_durations_are_errors() {
+        assert!(parse_duration(&"9".repeat(400)).is_err());
+        assert!(parse_duration("99999999999999999999999h").is_err());
+    }
+}